@@ -0,0 +1,530 @@
+use crate::utils::Env;
+use thiserror::Error;
+
+/*
+   operators from the lowest precedence
+   ,
+   = *= /= %= += -= <<= >>= &= ^= |=   (right to left)
+   ?:                                  (right to left)
+   ||
+   &&
+   |
+   ^
+   &
+   == !=
+   < > <= >=
+   << >>
+   + -
+   * / %
+   **                                  (right to left)
+   - + ! ~ ++ -- (prefix)
+   ++ -- (postfix)
+*/
+
+#[derive(Clone, Error, Debug)]
+pub enum ArithError {
+    #[error("division by zero")]
+    DivisionByZero,
+    #[error("exponent less than 0")]
+    NegativeExponent,
+    #[error("invalid number \"{0}\"")]
+    InvalidNumber(String),
+    #[error("unexpected token \"{0}\"")]
+    UnexpectedToken(String),
+    #[error("unexpected end of expression")]
+    UnexpectedEnd,
+    #[error("assignment to a non-variable")]
+    NotVariable,
+    #[error("expression recursion level exceeded")]
+    TooDeep,
+}
+
+#[derive(Clone, Debug)]
+enum Token {
+    Num(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+// longer operators are matched first
+const OPERATORS: [&str; 39] = [
+    "<<=", ">>=", "**", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "++", "--", "*=", "/=",
+    "%=", "+=", "-=", "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "&", "|", "^", "!", "~",
+    "?", ":", "=", "(", ")", ",",
+];
+
+// parses decimal, octal ("0" prefix) and hexadecimal ("0x" prefix) numbers
+fn parse_number(s: &str) -> Result<i64, ArithError> {
+    let res = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16)
+    } else if s.len() > 1 && s.starts_with('0') {
+        i64::from_str_radix(&s[1..], 8)
+    } else {
+        s.parse()
+    };
+    res.map_err(|_| ArithError::InvalidNumber(s.to_string()))
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, ArithError> {
+    let s = s.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < s.len() {
+        if s[i].is_whitespace() {
+            i += 1;
+        } else if s[i].is_ascii_alphanumeric() || s[i] == '_' {
+            let len = s[i..]
+                .iter()
+                .take_while(|c| c.is_ascii_alphanumeric() || **c == '_')
+                .count();
+            let word = s[i..i + len].iter().collect::<String>();
+            tokens.push(if s[i].is_ascii_digit() {
+                Token::Num(parse_number(&word)?)
+            } else {
+                Token::Name(word)
+            });
+            i += len;
+        } else {
+            let op = OPERATORS
+                .iter()
+                .find(|op| s[i..].iter().take(op.len()).copied().eq(op.chars()))
+                .ok_or_else(|| ArithError::UnexpectedToken(s[i].to_string()))?;
+            tokens.push(Token::Op(op));
+            i += op.len();
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Num(i64),
+    Var(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Cond(Box<Expr>, Box<Expr>, Box<Expr>),
+    // the operator is "=" or a compound one like "+="
+    Assign(&'static str, String, Box<Expr>),
+    // the variable, the difference, and whether the value after the change is returned
+    IncDec(String, i64, bool),
+}
+
+// how deep parentheses and operators like "-" and "**" are nested
+const MAX_NESTING: usize = 64;
+
+// binary operators of each precedence level, from the lowest
+const BINARY_LEVELS: [&[&str]; 10] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", ">", "<=", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    // how deep the expression being parsed is nested
+    depth: usize,
+}
+
+impl Parser {
+    // parses a nested expression with `f`. deep nesting is an error, not to overflow the stack
+    fn nested(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<Expr, ArithError>,
+    ) -> Result<Expr, ArithError> {
+        if self.depth >= MAX_NESTING {
+            return Err(ArithError::TooDeep);
+        }
+        self.depth += 1;
+        let res = f(self);
+        self.depth -= 1;
+        res
+    }
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+    fn expect(&mut self, op: &str) -> Result<(), ArithError> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(x)) if *x == op => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(token) => Err(unexpected(token)),
+            None => Err(ArithError::UnexpectedEnd),
+        }
+    }
+    // "x, y" evaluates both and is the value of y
+    fn parse_comma(&mut self) -> Result<Expr, ArithError> {
+        let mut lhs = self.parse_assign()?;
+        while self.peek_op() == Some(",") {
+            self.pos += 1;
+            let rhs = self.parse_assign()?;
+            lhs = Expr::Binary(",", Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+    fn parse_assign(&mut self) -> Result<Expr, ArithError> {
+        let lhs = self.parse_cond()?;
+        match self.peek_op() {
+            Some(
+                op @ ("=" | "*=" | "/=" | "%=" | "+=" | "-=" | "<<=" | ">>=" | "&=" | "^=" | "|="),
+            ) => {
+                self.pos += 1;
+                let name = match lhs {
+                    Expr::Var(name) => name,
+                    _ => return Err(ArithError::NotVariable),
+                };
+                Ok(Expr::Assign(
+                    op,
+                    name,
+                    Box::new(self.nested(Self::parse_assign)?),
+                ))
+            }
+            _ => Ok(lhs),
+        }
+    }
+    fn parse_cond(&mut self) -> Result<Expr, ArithError> {
+        let cond = self.parse_binary(0)?;
+        if self.peek_op() != Some("?") {
+            return Ok(cond);
+        }
+        self.pos += 1;
+        let then = self.nested(Self::parse_comma)?;
+        self.expect(":")?;
+        let els = self.nested(Self::parse_cond)?;
+        Ok(Expr::Cond(Box::new(cond), Box::new(then), Box::new(els)))
+    }
+    fn parse_binary(&mut self, level: usize) -> Result<Expr, ArithError> {
+        if level == BINARY_LEVELS.len() {
+            return self.parse_power();
+        }
+        let mut lhs = self.parse_binary(level + 1)?;
+        while let Some(op) = self
+            .peek_op()
+            .filter(|op| BINARY_LEVELS[level].contains(op))
+        {
+            self.pos += 1;
+            let rhs = self.parse_binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+    fn parse_power(&mut self) -> Result<Expr, ArithError> {
+        let lhs = self.parse_unary()?;
+        if self.peek_op() == Some("**") {
+            self.pos += 1;
+            let rhs = self.nested(Self::parse_power)?;
+            return Ok(Expr::Binary("**", Box::new(lhs), Box::new(rhs)));
+        }
+        Ok(lhs)
+    }
+    fn parse_unary(&mut self) -> Result<Expr, ArithError> {
+        match self.peek_op() {
+            Some(op @ ("-" | "+" | "!" | "~")) => {
+                self.pos += 1;
+                Ok(Expr::Unary(op, Box::new(self.nested(Self::parse_unary)?)))
+            }
+            Some(op @ ("++" | "--")) => {
+                self.pos += 1;
+                match self.nested(Self::parse_unary)? {
+                    Expr::Var(name) => {
+                        Ok(Expr::IncDec(name, if op == "++" { 1 } else { -1 }, true))
+                    }
+                    _ => Err(ArithError::NotVariable),
+                }
+            }
+            _ => self.parse_postfix(),
+        }
+    }
+    fn parse_postfix(&mut self) -> Result<Expr, ArithError> {
+        let expr = self.parse_primary()?;
+        match (expr, self.peek_op()) {
+            (Expr::Var(name), Some(op @ ("++" | "--"))) => {
+                self.pos += 1;
+                Ok(Expr::IncDec(name, if op == "++" { 1 } else { -1 }, false))
+            }
+            (expr, _) => Ok(expr),
+        }
+    }
+    fn parse_primary(&mut self) -> Result<Expr, ArithError> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or(ArithError::UnexpectedEnd)?;
+        self.pos += 1;
+        match token {
+            Token::Num(x) => Ok(Expr::Num(x)),
+            Token::Name(name) => Ok(Expr::Var(name)),
+            Token::Op("(") => {
+                let expr = self.nested(Self::parse_comma)?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            token => Err(unexpected(&token)),
+        }
+    }
+}
+
+fn unexpected(token: &Token) -> ArithError {
+    ArithError::UnexpectedToken(match token {
+        Token::Num(x) => x.to_string(),
+        Token::Name(name) => name.clone(),
+        Token::Op(op) => op.to_string(),
+    })
+}
+
+// how deep values of variables referring to other variables are evaluated
+const MAX_DEPTH: usize = 128;
+
+// unset or empty variables are 0. other values which are not numbers are evaluated as
+// expressions, so a variable may hold the name of another one
+fn get_value(name: &str, env: &mut Env, depth: usize) -> Result<i64, ArithError> {
+    let value = env.get_var(name).unwrap_or_default();
+    let value = value.trim();
+    if value.is_empty() {
+        Ok(0)
+    } else if value.starts_with(|c: char| c.is_ascii_digit()) {
+        parse_number(value)
+    } else {
+        evaluate_at(value, env, depth + 1)
+    }
+}
+
+fn apply_binary(op: &str, x: i64, y: i64) -> Result<i64, ArithError> {
+    Ok(match op {
+        "+" => x.wrapping_add(y),
+        "-" => x.wrapping_sub(y),
+        "*" => x.wrapping_mul(y),
+        "/" | "%" if y == 0 => return Err(ArithError::DivisionByZero),
+        "/" => x.wrapping_div(y),
+        "%" => x.wrapping_rem(y),
+        "**" if y < 0 => return Err(ArithError::NegativeExponent),
+        "**" => x.wrapping_pow(y.min(u32::MAX as i64) as u32),
+        "<<" => x.wrapping_shl(y as u32),
+        ">>" => x.wrapping_shr(y as u32),
+        "<" => (x < y) as i64,
+        ">" => (x > y) as i64,
+        "<=" => (x <= y) as i64,
+        ">=" => (x >= y) as i64,
+        "==" => (x == y) as i64,
+        "!=" => (x != y) as i64,
+        "&" => x & y,
+        "^" => x ^ y,
+        "|" => x | y,
+        _ => unreachable!(),
+    })
+}
+
+fn eval(expr: &Expr, env: &mut Env, depth: usize) -> Result<i64, ArithError> {
+    match expr {
+        Expr::Num(x) => Ok(*x),
+        Expr::Var(name) => get_value(name, env, depth),
+        Expr::Unary(op, x) => {
+            let x = eval(x, env, depth)?;
+            Ok(match *op {
+                "-" => x.wrapping_neg(),
+                "!" => (x == 0) as i64,
+                "~" => !x,
+                _ => x,
+            })
+        }
+        // logical operators are short-circuited
+        Expr::Binary("&&", x, y) => {
+            Ok((eval(x, env, depth)? != 0 && eval(y, env, depth)? != 0) as i64)
+        }
+        Expr::Binary("||", x, y) => {
+            Ok((eval(x, env, depth)? != 0 || eval(y, env, depth)? != 0) as i64)
+        }
+        Expr::Binary(",", x, y) => {
+            eval(x, env, depth)?;
+            eval(y, env, depth)
+        }
+        Expr::Binary(op, x, y) => {
+            let x = eval(x, env, depth)?;
+            let y = eval(y, env, depth)?;
+            apply_binary(op, x, y)
+        }
+        Expr::Cond(cond, then, els) => {
+            if eval(cond, env, depth)? != 0 {
+                eval(then, env, depth)
+            } else {
+                eval(els, env, depth)
+            }
+        }
+        Expr::Assign(op, name, x) => {
+            let y = eval(x, env, depth)?;
+            let value = match op.strip_suffix('=').filter(|x| !x.is_empty()) {
+                Some(op) => apply_binary(op, get_value(name, env, depth)?, y)?,
+                None => y,
+            };
+            env.set_var(name, &value.to_string());
+            Ok(value)
+        }
+        Expr::IncDec(name, diff, prefix) => {
+            let old = get_value(name, env, depth)?;
+            let new = old.wrapping_add(*diff);
+            env.set_var(name, &new.to_string());
+            Ok(if *prefix { new } else { old })
+        }
+    }
+}
+
+// evaluates an arithmetic expression. an empty expression is 0
+pub fn evaluate(s: &str, env: &mut Env) -> Result<i64, ArithError> {
+    evaluate_at(s, env, 0)
+}
+
+fn evaluate_at(s: &str, env: &mut Env, depth: usize) -> Result<i64, ArithError> {
+    if depth > MAX_DEPTH {
+        return Err(ArithError::TooDeep);
+    }
+    let tokens = tokenize(s)?;
+    if tokens.is_empty() {
+        return Ok(0);
+    }
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        depth: 0,
+    };
+    let expr = parser.parse_comma()?;
+    if let Some(token) = parser.tokens.get(parser.pos) {
+        return Err(unexpected(token));
+    }
+    eval(&expr, env, depth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calc(s: &str) -> i64 {
+        evaluate(s, &mut Env::for_test()).unwrap()
+    }
+
+    #[test]
+    fn precedence_and_associativity() {
+        assert_eq!(calc("1 + 2 * 3"), 7);
+        assert_eq!(calc("(1 + 2) * 3"), 9);
+        assert_eq!(calc("10 - 4 - 3"), 3);
+        assert_eq!(calc("64 / 4 / 2"), 8);
+        assert_eq!(calc("2 ** 3 ** 2"), 512);
+        assert_eq!(calc("-2 ** 2"), 4);
+        assert_eq!(calc("1 + 2 << 1"), 6);
+        assert_eq!(calc("1 < 2 == 1"), 1);
+        assert_eq!(calc("6 & 3 ^ 1 | 8"), 11);
+        assert_eq!(calc("1 || 0 && 0"), 1);
+        assert_eq!(calc("0 ? 1 : 0 ? 2 : 3"), 3);
+        assert_eq!(calc("!0 + ~0"), 0);
+        assert_eq!(calc("010 + 0x1f + 0X10"), 55);
+        assert_eq!(calc(""), 0);
+    }
+
+    #[test]
+    fn overflow_wraps() {
+        assert_eq!(calc("9223372036854775807 + 1"), i64::MIN);
+        assert_eq!(calc("-9223372036854775807 - 2"), i64::MAX);
+        assert_eq!(calc("2 ** 64"), 0);
+        assert_eq!(calc("(-9223372036854775807 - 1) / -1"), i64::MIN);
+        assert_eq!(calc("(-9223372036854775807 - 1) % -1"), 0);
+    }
+
+    #[test]
+    fn errors() {
+        let mut env = Env::for_test();
+        let mut error = |s: &str| evaluate(s, &mut env).unwrap_err();
+        assert!(matches!(error("1 / 0"), ArithError::DivisionByZero));
+        assert!(matches!(error("1 % (2 - 2)"), ArithError::DivisionByZero));
+        assert!(matches!(error("2 ** -1"), ArithError::NegativeExponent));
+        assert!(matches!(error("08"), ArithError::InvalidNumber(x) if x == "08"));
+        assert!(matches!(error("1 +"), ArithError::UnexpectedEnd));
+        assert!(matches!(error("(1"), ArithError::UnexpectedEnd));
+        assert!(matches!(error("1 2"), ArithError::UnexpectedToken(x) if x == "2"));
+        assert!(matches!(error("1 $ 2"), ArithError::UnexpectedToken(x) if x == "$"));
+        assert!(matches!(error("1 = 2"), ArithError::NotVariable));
+        assert!(matches!(error("++1"), ArithError::NotVariable));
+        // deep nesting is an error instead of a stack overflow
+        let nested = format!("{}1{}", "(".repeat(100000), ")".repeat(100000));
+        assert!(matches!(error(&nested), ArithError::TooDeep));
+        assert!(matches!(error(&"-".repeat(100000)), ArithError::TooDeep));
+        assert!(matches!(
+            error(&"2 ** ".repeat(100000)),
+            ArithError::TooDeep
+        ));
+        assert_eq!(calc(&format!("{}1{}", "(".repeat(50), ")".repeat(50))), 1);
+    }
+
+    #[test]
+    fn assignments() {
+        let mut env = Env::for_test();
+        let mut calc = |s: &str| evaluate(s, &mut env).unwrap();
+        assert_eq!(calc("ar_x = ar_y = 6"), 6);
+        assert_eq!(calc("ar_x += 2"), 8);
+        assert_eq!(calc("ar_x -= 1"), 7);
+        assert_eq!(calc("ar_x *= 3"), 21);
+        assert_eq!(calc("ar_x /= 2"), 10);
+        assert_eq!(calc("ar_x %= 4"), 2);
+        assert_eq!(calc("ar_x <<= 3"), 16);
+        assert_eq!(calc("ar_x >>= 1"), 8);
+        assert_eq!(calc("ar_x |= 3"), 11);
+        assert_eq!(calc("ar_x &= 6"), 2);
+        assert_eq!(calc("ar_x ^= 7"), 5);
+        assert_eq!(calc("ar_x++ + ar_x"), 11);
+        assert_eq!(calc("--ar_x"), 5);
+        assert_eq!(calc("ar_x-- * 2"), 10);
+        assert_eq!(calc("ar_x + ar_y"), 10);
+        assert_eq!(env.get_var("ar_x").as_deref(), Some("4"));
+    }
+
+    #[test]
+    fn short_circuits() {
+        let mut env = Env::for_test();
+        let mut calc = |s: &str| evaluate(s, &mut env).unwrap();
+        assert_eq!(calc("0 && (ar_a = 1)"), 0);
+        assert_eq!(calc("1 || (ar_a = 1)"), 1);
+        assert_eq!(calc("1 ? 2 : (ar_a = 1)"), 2);
+        assert_eq!(calc("0 ? (ar_a = 1) : 3"), 3);
+        assert_eq!(calc("0 && 1 / 0"), 0);
+        assert_eq!(calc("ar_a"), 0);
+        assert_eq!(calc("1 && (ar_a = 5)"), 1);
+        assert_eq!(calc("ar_a"), 5);
+    }
+
+    #[test]
+    fn variables_refer_to_other_variables() {
+        let mut env = Env::for_test();
+        env.set_var("ar_ref", "ar_target");
+        env.set_var("ar_target", "3");
+        env.set_var("ar_expr", "ar_target * 2");
+        env.set_var("ar_self", "ar_self");
+        env.set_var("ar_space", " 7 ");
+        let mut calc = |s: &str| evaluate(s, &mut env);
+        assert_eq!(calc("ar_ref + 1").unwrap(), 4);
+        assert_eq!(calc("ar_expr + 1").unwrap(), 7);
+        assert_eq!(calc("ar_space").unwrap(), 7);
+        assert_eq!(calc("ar_unset").unwrap(), 0);
+        assert!(matches!(calc("ar_self"), Err(ArithError::TooDeep)));
+    }
+
+    #[test]
+    fn comma_operator() {
+        let mut env = Env::for_test();
+        let mut calc = |s: &str| evaluate(s, &mut env).unwrap();
+        assert_eq!(calc("ar_i = 1, ar_j = 2, ar_i + ar_j"), 3);
+        assert_eq!(calc("(1, 2) * 3"), 6);
+        assert_eq!(calc("1 ? 2, 3 : 4"), 3);
+        assert_eq!(calc("ar_i++, ar_j--"), 2);
+        assert_eq!(calc("ar_i * 10 + ar_j"), 21);
+    }
+}
@@ -1,28 +1,80 @@
-use crate::lexer::{lex, Token};
+use crate::expand::expand_tilde;
+use crate::lexer::{ends_in_comment, lex, Token};
 use crate::parser::{make_parse_tree_from_tokens, ParseError};
-use crate::println2;
 use crate::utils::Env;
 use crate::utils::ErrorEnum;
-use std::fs::{File, ReadDir};
-use std::io::Error;
-use std::num::ParseIntError;
-use std::path::{Path, PathBuf};
+use nix::libc;
+use std::ffi::CStr;
 
 #[derive(Debug)]
 enum CompType {
     Bin(Option<String>),
     Path((Option<String>, bool)),
+    // the head of a variable name after '$' or "${"
+    Var(String),
     Invalid,
 }
 
-fn get_comp_type(input: &String, env: &Env) -> CompType {
-    let tokens = lex(&input).unwrap();
+// user names in the passwd database, looked up through NSS as `expand_tilde` does
+fn user_names() -> Vec<String> {
+    let mut res = Vec::new();
+    // getpwent is not thread-safe, but the shell completes only in the main thread
+    unsafe {
+        libc::setpwent();
+        loop {
+            let entry = libc::getpwent();
+            if entry.is_null() {
+                break;
+            }
+            res.push(
+                CStr::from_ptr((*entry).pw_name)
+                    .to_string_lossy()
+                    .into_owned(),
+            );
+        }
+        libc::endpwent();
+    }
+    res
+}
+
+// returns the variable name at the end of `input` if it follows '$' or "${"
+fn var_prefix(input: &str) -> Option<String> {
+    let name = input
+        .chars()
+        .rev()
+        .take_while(|&c| c.is_ascii_alphanumeric() || c == '_')
+        .collect::<String>()
+        .chars()
+        .rev()
+        .collect::<String>();
+    let rest = &input[..input.len() - name.len()];
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        None
+    } else if rest.ends_with('$') || rest.ends_with("${") {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+fn get_comp_type(input: &str) -> CompType {
+    if ends_in_comment(input) {
+        return CompType::Invalid;
+    }
+    if let Some(name) = var_prefix(input) {
+        return CompType::Var(name);
+    }
+    let (tokens, spans): (Vec<_>, Vec<_>) = match lex(input) {
+        Ok(tokens) => tokens.into_iter().unzip(),
+        Err(_) => return CompType::Invalid,
+    };
     if tokens.is_empty() {
         return CompType::Bin(None);
     }
-    let last_whitespace = input.chars().last().unwrap().is_whitespace();
+    // the cursor is in the last token if it ends at the end of input
+    let in_last_token = spans.last().unwrap().end == input.chars().count();
     let last_token = tokens.last().cloned().unwrap();
-    let last_token_str = if !last_whitespace {
+    let last_token_str = if in_last_token {
         if let Token::String(s) = last_token {
             Some(s)
         } else {
@@ -36,7 +88,7 @@ fn get_comp_type(input: &String, env: &Env) -> CompType {
     } else {
         tokens.clone()
     };
-    let parse_result = make_parse_tree_from_tokens(check_tokens.clone(), &env);
+    let parse_result = make_parse_tree_from_tokens(check_tokens.clone());
     let res = match &parse_result {
         Ok(stmt) if stmt.last_empty => CompType::Bin(last_token_str.clone()),
         Ok(_) => CompType::Path((last_token_str.clone(), true)),
@@ -48,11 +100,15 @@ fn get_comp_type(input: &String, env: &Env) -> CompType {
         {
             CompType::Path((last_token_str.clone(), true))
         }
+        // arguments in an unclosed compound command
+        Err(ErrorEnum::ParseError(ParseError::MissingEnd(_, i))) if *i == check_tokens.len() => {
+            CompType::Path((last_token_str.clone(), true))
+        }
         _ => CompType::Invalid,
     };
     match match res {
         CompType::Bin(s)
-            if s.clone().map_or(false, |x| {
+            if s.clone().is_some_and(|x| {
                 x.starts_with('~') || x.starts_with('.') || x.starts_with('/')
             }) =>
         {
@@ -60,7 +116,6 @@ fn get_comp_type(input: &String, env: &Env) -> CompType {
         }
         _ => res,
     } {
-        CompType::Bin(bin) => CompType::Bin(bin),
         CompType::Path((path, _)) => {
             let access_idx = if last_token_str.map_or(0, |x| x.len()) == 0 {
                 1
@@ -76,12 +131,12 @@ fn get_comp_type(input: &String, env: &Env) -> CompType {
                 CompType::Path((path, true))
             }
         }
-        CompType::Invalid => CompType::Invalid,
+        res => res,
     }
 }
 
 pub fn comp(input: String, env: &mut Env) -> (usize, Vec<String>) {
-    match get_comp_type(&input, env) {
+    match get_comp_type(&input) {
         CompType::Bin(path) => {
             let fin_pos = input.len() - path.clone().map_or(0, |x| x.len());
             let path = path.unwrap_or("".to_string());
@@ -97,8 +152,19 @@ pub fn comp(input: String, env: &mut Env) -> (usize, Vec<String>) {
         }
         CompType::Path(path) => {
             let fin_pos = input.len() - path.clone().0.map_or(0, |x| x.len());
-            if path.0.is_some() && path.clone().0.unwrap() == "~" {
-                return (0, Vec::new());
+            if let Some(name) = path.0.as_ref().and_then(|x| x.strip_prefix('~')) {
+                if !name.contains('/') {
+                    // user names after '~'
+                    let mut users = user_names()
+                        .into_iter()
+                        .filter(|x| x.starts_with(name))
+                        .map(|x| x + "/")
+                        .collect::<Vec<_>>();
+                    // a name may be in more than one source of NSS
+                    users.sort();
+                    users.dedup();
+                    return (input.len() - name.len(), users);
+                }
             }
             if path.0.is_some() && path.clone().0.unwrap() == "." {
                 return (fin_pos, vec!["./".to_string(), "../".to_string()]);
@@ -106,23 +172,20 @@ pub fn comp(input: String, env: &mut Env) -> (usize, Vec<String>) {
             if path.0.is_some() && path.clone().0.unwrap() == ".." {
                 return (fin_pos, vec!["../".to_string()]);
             }
-            if path.0.is_some()
-                && path.clone().0.unwrap().starts_with("~")
-                && !path.clone().0.unwrap().starts_with("~/")
-            {
-                return (0, Vec::new());
-            }
             let file_ok = path.1;
-            let (path, ofs_minus) = match path.0 {
-                None => ("./".to_string(), 2),
-                Some(path) => match path.strip_prefix("~") {
-                    Some(path) => (
-                        env.home_dir.display().to_string() + path,
-                        env.home_dir.display().to_string().len() - 1,
-                    ),
-                    None if path.starts_with("/") || path.starts_with(".") => (path, 0),
-                    None => ("./".to_string() + &path, 2),
-                },
+            // the path is rewritten for searching: `ofs_minus` chars are added to the head, and
+            // `ofs_plus` chars are removed from it
+            let (path, ofs_minus, ofs_plus) = match path.0 {
+                None => ("./".to_string(), 2, 0),
+                Some(path) if path.starts_with('~') => {
+                    let idx = path.find('/').unwrap();
+                    match expand_tilde(&path[1..idx], env) {
+                        Some(dir) => (dir.clone() + &path[idx..], dir.len(), idx),
+                        None => return (0, Vec::new()),
+                    }
+                }
+                Some(path) if path.starts_with("/") || path.starts_with(".") => (path, 0, 0),
+                Some(path) => ("./".to_string() + &path, 2, 0),
             };
             let pos = path.rfind('/').map(|x| x + 1).unwrap_or(0);
             let (path_parent, query) = match path.rfind('/') {
@@ -157,10 +220,10 @@ pub fn comp(input: String, env: &mut Env) -> (usize, Vec<String>) {
             };
             let mut matches = files
                 .iter()
-                .cloned()
                 .filter(|x| {
                     (query.starts_with(".") || !x.0.starts_with(".")) && x.0.starts_with(&query)
                 })
+                .cloned()
                 .collect::<Vec<_>>();
             matches.sort_by(|x, y| {
                 x.0.starts_with(".")
@@ -169,14 +232,25 @@ pub fn comp(input: String, env: &mut Env) -> (usize, Vec<String>) {
                     .then(x.1.cmp(&y.1))
                     .reverse()
             });
-            let mut matches: Vec<String> = matches.iter().map(|(x, y)| x).cloned().collect();
+            let mut matches: Vec<String> = matches.iter().map(|(x, _)| x).cloned().collect();
             if "./".starts_with(&query) {
                 matches.push("./".to_string());
             }
             if "../".starts_with(&query) {
                 matches.push("../".to_string());
             }
-            (fin_pos + pos - ofs_minus, matches)
+            (fin_pos + pos + ofs_plus - ofs_minus, matches)
+        }
+        CompType::Var(name) => {
+            let fin_pos = input.len() - name.len();
+            let mut v = env
+                .vars
+                .keys()
+                .filter(|x| x.starts_with(&name))
+                .cloned()
+                .collect::<Vec<_>>();
+            v.sort();
+            (fin_pos, v)
         }
         CompType::Invalid => (0, Vec::new()),
     }
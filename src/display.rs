@@ -1,9 +1,8 @@
+use crate::parser;
 use crate::utils::Env;
 use colored::Colorize;
-use nix::errno::Errno;
 use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
 use nix::unistd::read;
-use println;
 use std::io::{stdout, Write};
 
 use crate::println2;
@@ -18,6 +17,17 @@ pub struct Display {
     cur: usize,
     history_cur: Option<usize>,
     suggestion: Option<Vec<char>>,
+    // lines of an unfinished command, which are waiting for the continuation
+    pending: Vec<String>,
+}
+
+// newlines of a multi-line command are shown in one line
+fn visible(ch: char) -> char {
+    if ch == '\n' {
+        '⏎'
+    } else {
+        ch
+    }
 }
 
 impl Display {
@@ -27,6 +37,7 @@ impl Display {
             cur: 0,
             history_cur: None,
             suggestion: None,
+            pending: Vec::new(),
         }
     }
     pub fn clear(&mut self) {
@@ -37,7 +48,7 @@ impl Display {
         let mut attr = tcgetattr(0).unwrap();
         let bef = attr.clone();
         cfmakeraw(&mut attr);
-        tcsetattr(0, SetArg::TCSANOW, &attr);
+        tcsetattr(0, SetArg::TCSANOW, &attr).unwrap();
         let res = self.stdin_read(env);
         self.restore_cursor();
         tcsetattr(0, SetArg::TCSANOW, &bef).unwrap();
@@ -52,7 +63,7 @@ impl Display {
         stdout().flush().unwrap();
     }
     fn set_cmd(&mut self, cmd: String) {
-        print!("{}", cmd);
+        print!("{}", cmd.chars().map(visible).collect::<String>());
         stdout().flush().unwrap();
         self.cmd = cmd.chars().collect();
         self.cur = 0;
@@ -73,18 +84,14 @@ impl Display {
         self.cur = 0;
     }
     fn add_char(&mut self, ch: char) {
-        let mut buf = vec![0 as char; self.cur];
-        for i in 0..self.cur {
-            buf[i] = self.cmd.last().unwrap().clone();
-            self.cmd.pop();
-        }
+        let buf = self.cmd.split_off(self.cmd.len() - self.cur);
         print!("\x1b[J");
         self.cmd.push(ch);
-        print!("{}", ch);
+        print!("{}", visible(ch));
         stdout().flush().unwrap();
-        for &ch in buf.iter().rev() {
+        for &ch in buf.iter() {
             self.cmd.push(ch);
-            print!("{}", ch);
+            print!("{}", visible(ch));
         }
         if self.cur != 0 {
             print!("\x1b[{}D", self.cur);
@@ -92,6 +99,12 @@ impl Display {
         stdout().flush().unwrap();
     }
     pub fn write_header(&self, env: &Env) {
+        if !self.pending.is_empty() {
+            // continuation prompt (PS2)
+            print!("> ");
+            std::io::stdout().flush().unwrap();
+            return;
+        }
         let currenct_dir = match std::env::current_dir() {
             Ok(path) => path.display().to_string(),
             Err(_) => "???".to_string(),
@@ -136,7 +149,7 @@ impl Display {
                                     'A' => {
                                         self.reset_cmd();
                                         self.history_cur = match self.history_cur {
-                                            Some(x) if x == 0 => None,
+                                            Some(0) => None,
                                             Some(x) => Some(x - 1),
                                             None => Some(env.history.len() - 1),
                                         };
@@ -155,19 +168,15 @@ impl Display {
                                             self.set_cmd(env.history[idx].1.clone());
                                         }
                                     }
-                                    'C' => {
-                                        if self.cur != 0 {
-                                            self.cur -= 1;
-                                            print!("\x1b[C");
-                                            stdout().flush().unwrap();
-                                        }
+                                    'C' if self.cur != 0 => {
+                                        self.cur -= 1;
+                                        print!("\x1b[C");
+                                        stdout().flush().unwrap();
                                     }
-                                    'D' => {
-                                        if self.cur != self.cmd.len() {
-                                            self.cur += 1;
-                                            print!("\x1b[D");
-                                            stdout().flush().unwrap();
-                                        }
+                                    'D' if self.cur != self.cmd.len() => {
+                                        self.cur += 1;
+                                        print!("\x1b[D");
+                                        stdout().flush().unwrap();
                                     }
                                     _ => {}
                                 }
@@ -183,9 +192,15 @@ impl Display {
                                 print!("\x1b[J");
                                 println2!();
                                 stdout().flush().unwrap();
-                                let cmd = self.cmd.iter().collect();
+                                self.pending.push(self.cmd.iter().collect());
                                 self.cmd = Vec::new();
                                 self.history_cur = None;
+                                let cmd = self.pending.join("\n");
+                                if parser::is_incomplete(&cmd) {
+                                    self.write_header(env);
+                                    continue;
+                                }
+                                self.pending.clear();
                                 return ReadEnum::Command(cmd);
                             }
                             '\t' => {
@@ -210,7 +225,8 @@ impl Display {
                                     self.restore_cursor();
                                     print!("\x1b[J");
                                     println2!();
-                                    self.write_header(&env);
+                                    self.pending.clear();
+                                    self.write_header(env);
                                     stdout().flush().unwrap();
                                     self.cmd = Vec::new();
                                     self.history_cur = None;
@@ -220,7 +236,7 @@ impl Display {
                                     self.restore_cursor();
                                     print!("\x1b[2J");
                                     print!("\x1b[0;0H");
-                                    self.write_header(&env);
+                                    self.write_header(env);
                                     stdout().flush().unwrap();
                                     self.cmd = Vec::new();
                                     self.history_cur = None;
@@ -242,7 +258,6 @@ impl Display {
                 }
             }
         }
-        unreachable!()
     }
     pub fn apply_suggestion(&mut self) {
         if let Some(s) = &self.suggestion {
@@ -254,7 +269,7 @@ impl Display {
         stdout().flush().unwrap();
         self.suggestion = None;
     }
-    pub fn write_comp(&mut self, input: &String, comp: Vec<String>, ofs: usize, env: &Env) {
+    pub fn write_comp(&mut self, input: &str, comp: Vec<String>, ofs: usize, env: &Env) {
         print!("\x1b[J");
         // set margin
         print!("\x1b[3B");
@@ -263,8 +278,8 @@ impl Display {
         print!("\x1b[J");
         // write data
         print!("\x1b[1000D");
-        self.write_header(&env);
-        print!("{}", input);
+        self.write_header(env);
+        print!("{}", input.chars().map(visible).collect::<String>());
 
         // store cursor
         print!("\x1b7");
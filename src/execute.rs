@@ -1,13 +1,22 @@
+use crate::expand::{expand_arith_expr, expand_heredoc, expand_single, to_string, ExpansionError};
 use crate::lexer::*;
 use crate::parser::*;
-use crate::println2;
-use crate::utils::Env;
+use crate::pattern::matches;
+use crate::time;
 use crate::utils::ErrorEnum;
-use nix::errno::Errno;
+use crate::utils::{is_name, replace_tokens, Env};
+use crate::{eprintln2, println2};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
 use nix::sys::wait::{WaitPidFlag, WaitStatus};
-use nix::unistd::{close, dup2, fork, pipe, read, write, ForkResult};
+use nix::unistd::{
+    close, dup2, fork, lseek, mkstemp, pipe, unlink, write, ForkResult, Pid, Whence,
+};
 use std::env;
 use std::ffi::{CStr, CString};
+use std::fmt::Display;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 use thiserror::Error;
 
 #[derive(Clone, Error, Debug)]
@@ -23,8 +32,34 @@ pub enum CdError {
 pub enum HistoryError {
     #[error("too many argument (expected 0, found: {0})")]
     TooManyArgument(usize),
-    #[error("{0}")]
-    ExecError(String),
+}
+
+#[derive(Clone, Error, Debug)]
+pub enum SetError {
+    #[error("invalid option: {0}")]
+    InvalidOption(String),
+}
+
+#[derive(Clone, Error, Debug)]
+pub enum VarError {
+    #[error("not a valid identifier: {0}")]
+    InvalidName(String),
+}
+
+#[derive(Clone, Error, Debug)]
+pub enum LoopError {
+    #[error("only meaningful in a loop")]
+    NotInLoop,
+    #[error("{0}: loop count out of range")]
+    InvalidCount(String),
+}
+
+#[derive(Clone, Error, Debug)]
+pub enum FunctionError {
+    #[error("can only be used in a function")]
+    NotInFunction,
+    #[error("{0}: numeric argument required")]
+    InvalidStatus(String),
 }
 
 #[derive(Clone, Error, Debug)]
@@ -39,8 +74,6 @@ pub enum ExecutionError {
     DupError(String),
     #[error("failed to close a file descriptor: {0}")]
     CloseError(String),
-    #[error("command not found: {0}")]
-    NotFoundError(String),
     #[error("error caused while executing: {0}")]
     ExecError(String),
     #[error("error caused in \"{0}\"")]
@@ -53,34 +86,98 @@ pub enum ExecutionError {
     ForkError(String),
     #[error("pipe error ({0})")]
     PipeError(String),
-    #[error("command is empty")]
-    CommandIsEmpty,
     #[error("statement is empty")]
     StatementIsEmpty,
     #[error("cd error")]
     CdError(CdError),
     #[error("history error")]
     HistoryError(HistoryError),
+    #[error("set error")]
+    SetError(SetError),
+    // the name of the builtin and the error
+    #[error("variable error")]
+    VarError(String, VarError),
+    #[error("expansion error")]
+    ExpansionError(ExpansionError),
+    // the name of the builtin and the error
+    #[error("loop error")]
+    LoopError(String, LoopError),
+    // the number of loops to leave
+    #[error("break")]
+    Break(usize),
+    #[error("continue")]
+    Continue(usize),
+    // the name of the builtin and the error
+    #[error("function error")]
+    FunctionError(String, FunctionError),
+    // the status of the function
+    #[error("return")]
+    Return(i32),
     #[error("exit")]
     Exit,
 }
 
-fn exec_and_fork(command: Command) -> Result<i32, ExecutionError> {
-    match unsafe { fork() } {
-        Ok(ForkResult::Parent { child }) => {
-            match nix::sys::wait::waitpid(child, Some(WaitPidFlag::WCONTINUED)) {
-                Ok(WaitStatus::Exited(_, status)) => Ok(status),
-                Ok(WaitStatus::Signaled(_, nix::sys::signal::Signal::SIGINT, _)) => {
-                    Err(ExecutionError::InterruptError)
-                }
-                Ok(WaitStatus::Signaled(_, nix::sys::signal::Signal::SIGQUIT, _)) => {
-                    Err(ExecutionError::QuitError)
-                }
-                Err(err) => Err(ExecutionError::ExecError(err.to_string())),
-                _ => Err(ExecutionError::ExecOtherError(command.to_string())),
-            }
+impl ExecutionError {
+    // errors which stop the rest of the statement, instead of failing one command
+    fn is_control(&self) -> bool {
+        matches!(
+            self,
+            ExecutionError::Exit
+                | ExecutionError::InterruptError
+                | ExecutionError::QuitError
+                | ExecutionError::Break(_)
+                | ExecutionError::Continue(_)
+                | ExecutionError::Return(_)
+        )
+    }
+}
+
+// set by the handler of SIGINT, so that loops stop even if the shell itself receives it
+pub static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// waits for a child, and adds its resource usage for "time"
+fn wait_child(child: Pid, command: &impl Display, env: &mut Env) -> Result<i32, ExecutionError> {
+    let (status, usage) = time::wait4(child, Some(WaitPidFlag::WCONTINUED))
+        .map_err(|err| ExecutionError::ExecError(err.to_string()))?;
+    env.child_usage.add(usage);
+    match status {
+        WaitStatus::Exited(_, status) => Ok(status),
+        WaitStatus::Signaled(_, nix::sys::signal::Signal::SIGINT, _) => {
+            Err(ExecutionError::InterruptError)
+        }
+        WaitStatus::Signaled(_, nix::sys::signal::Signal::SIGQUIT, _) => {
+            Err(ExecutionError::QuitError)
+        }
+        // the reader of the pipe has exited
+        WaitStatus::Signaled(_, nix::sys::signal::Signal::SIGPIPE, _) => {
+            Ok(128 + nix::sys::signal::Signal::SIGPIPE as i32)
+        }
+        _ => Err(ExecutionError::ExecOtherError(command.to_string())),
+    }
+}
+
+// waits for the stages of a pipeline other than the last one
+fn wait_stages(children: Vec<Pid>, env: &mut Env) {
+    for child in children {
+        if let Ok((_, usage)) = time::wait4(child, None) {
+            env.child_usage.add(usage);
         }
+    }
+}
+
+fn exec_and_fork(command: Command, env: &mut Env) -> Result<i32, ExecutionError> {
+    match unsafe { fork() } {
+        Ok(ForkResult::Parent { child }) => wait_child(child, &command, env),
         Ok(ForkResult::Child) => unsafe {
+            // the shell ignores SIGPIPE, and the ignored signal is inherited over exec
+            let _ = nix::sys::signal::signal(
+                nix::sys::signal::Signal::SIGPIPE,
+                nix::sys::signal::SigHandler::SigDfl,
+            );
+            // assignments before the command are set only in its environment
+            for (name, value) in &command.assignments {
+                env::set_var(name, value);
+            }
             let cstr = CString::new(command.str[0].clone()).unwrap();
             let cstr = CStr::from_bytes_with_nul_unchecked(cstr.to_bytes_with_nul());
             let argv = command
@@ -91,7 +188,7 @@ fn exec_and_fork(command: Command) -> Result<i32, ExecutionError> {
             match nix::unistd::execvp(cstr, &argv) {
                 Ok(_) => std::process::exit(0),
                 Err(_) => {
-                    println2!("myshell: command not found: {}", command.str[0]);
+                    eprintln2!("myshell: command not found: {}", command.str[0]);
                     std::process::exit(-1)
                 }
             }
@@ -100,7 +197,7 @@ fn exec_and_fork(command: Command) -> Result<i32, ExecutionError> {
     }
 }
 
-fn exec_cd(command: Command) -> Result<i32, ExecutionError> {
+fn exec_cd(command: Command, env: &mut Env) -> Result<i32, ExecutionError> {
     if command.str.len() == 1 {
         Err(ExecutionError::CdError(CdError::MissingArgugment))
     } else if command.str.len() > 2 {
@@ -108,8 +205,18 @@ fn exec_cd(command: Command) -> Result<i32, ExecutionError> {
             command.str.len() - 1,
         )))
     } else {
+        let old_dir = env::current_dir().map(|x| x.display().to_string());
         match env::set_current_dir(&command.str[1]) {
-            Ok(_) => Ok(0),
+            Ok(_) => {
+                // for "~+" and "~-"
+                if let Ok(old_dir) = old_dir {
+                    env.set_var("OLDPWD", &old_dir);
+                }
+                if let Ok(dir) = env::current_dir() {
+                    env.set_var("PWD", &dir.display().to_string());
+                }
+                Ok(0)
+            }
             Err(err) => Err(ExecutionError::CdError(CdError::ExecError(err.to_string()))),
         }
     }
@@ -128,89 +235,647 @@ fn exec_history(command: Command, env: &Env) -> Result<i32, ExecutionError> {
     }
 }
 
-fn exec_command_internal(command: Command, env: &Env) -> Result<i32, ExecutionError> {
-    assert!(!command.str.is_empty());
+// options of "set -o"
+fn option_mut<'a>(env: &'a mut Env, name: &str) -> Option<&'a mut bool> {
+    match name {
+        "failglob" => Some(&mut env.failglob),
+        "noclobber" => Some(&mut env.noclobber),
+        "nullglob" => Some(&mut env.nullglob),
+        _ => None,
+    }
+}
+
+fn exec_set(command: Command, env: &mut Env) -> Result<i32, ExecutionError> {
+    let invalid_option = |x: &str| ExecutionError::SetError(SetError::InvalidOption(x.to_string()));
+    let mut args = command.str.iter().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-C" => env.noclobber = true,
+            "+C" => env.noclobber = false,
+            "-o" | "+o" => match args.next() {
+                Some(name) => match option_mut(env, name) {
+                    Some(option) => *option = arg == "-o",
+                    None => return Err(invalid_option(name)),
+                },
+                None => {
+                    for name in ["failglob", "noclobber", "nullglob"] {
+                        let on = *option_mut(env, name).unwrap();
+                        println!("{}\t{}", name, if on { "on" } else { "off" });
+                    }
+                }
+            },
+            // the rest are positional parameters
+            "--" => {
+                env.params = args.by_ref().cloned().collect();
+            }
+            _ => return Err(invalid_option(arg)),
+        }
+    }
+    Ok(0)
+}
+
+fn exec_export(command: Command, env: &mut Env) -> Result<i32, ExecutionError> {
+    if command.str.len() == 1 {
+        let mut vars = env::vars().collect::<Vec<_>>();
+        vars.sort();
+        for (name, value) in vars {
+            println!("export {}=\"{}\"", name, value);
+        }
+        return Ok(0);
+    }
+    for arg in command.str.iter().skip(1) {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        if !is_name(name) {
+            return Err(ExecutionError::VarError(
+                "export".to_string(),
+                VarError::InvalidName(arg.clone()),
+            ));
+        }
+        if let Some(value) = value {
+            env.set_var(name, value);
+        }
+        env.export_var(name);
+    }
+    Ok(0)
+}
+
+fn exec_unset(command: Command, env: &mut Env) -> Result<i32, ExecutionError> {
+    for name in command.str.iter().skip(1) {
+        if !is_name(name) {
+            return Err(ExecutionError::VarError(
+                "unset".to_string(),
+                VarError::InvalidName(name.clone()),
+            ));
+        }
+        env.unset_var(name);
+    }
+    Ok(0)
+}
+
+// "break n" and "continue n"
+fn exec_break(command: Command, env: &Env) -> Result<i32, ExecutionError> {
+    let name = command.str[0].clone();
+    if env.loop_depth == 0 {
+        return Err(ExecutionError::LoopError(name, LoopError::NotInLoop));
+    }
+    let count = match command.str.get(1) {
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(count) if count != 0 => count,
+            _ => {
+                return Err(ExecutionError::LoopError(
+                    name,
+                    LoopError::InvalidCount(arg.clone()),
+                ))
+            }
+        },
+        None => 1,
+    };
+    // leaves the outermost loop if `count` is too large
+    let count = count.min(env.loop_depth);
+    if name == "break" {
+        Err(ExecutionError::Break(count))
+    } else {
+        Err(ExecutionError::Continue(count))
+    }
+}
+
+// reads a line from stdin one byte at a time, so that the rest is left for following commands
+fn read_line(raw: bool) -> Option<String> {
+    let mut line = Vec::new();
+    let mut buf = [0u8; 1];
+    loop {
+        match nix::unistd::read(0, &mut buf) {
+            Ok(1) => {}
+            Err(nix::errno::Errno::EINTR) => continue,
+            // EOF
+            _ => {
+                return if line.is_empty() {
+                    None
+                } else {
+                    Some(String::from_utf8_lossy(&line).to_string())
+                };
+            }
+        }
+        match buf[0] {
+            b'\n' => return Some(String::from_utf8_lossy(&line).to_string()),
+            // a backslash escapes the next char, and a backslash-newline continues the line
+            b'\\' if !raw => {
+                if let Ok(1) = nix::unistd::read(0, &mut buf) {
+                    if buf[0] != b'\n' {
+                        line.push(buf[0]);
+                    }
+                }
+            }
+            c => line.push(c),
+        }
+    }
+}
+
+// "read [-r] [name...]". the line is split by IFS, and the last name takes the rest of it
+fn exec_read(command: Command, env: &mut Env) -> Result<i32, ExecutionError> {
+    let mut args = &command.str[1..];
+    let raw = args.first().is_some_and(|x| x == "-r");
+    if raw {
+        args = &args[1..];
+    }
+    let names = if args.is_empty() {
+        vec!["REPLY".to_string()]
+    } else {
+        args.to_vec()
+    };
+    if let Some(name) = names.iter().find(|x| !is_name(x)) {
+        return Err(ExecutionError::VarError(
+            "read".to_string(),
+            VarError::InvalidName(name.clone()),
+        ));
+    }
+    let line = read_line(raw);
+    let ifs = env
+        .get_var("IFS")
+        .unwrap_or_else(|| " \t\n".to_string())
+        .chars()
+        .collect::<Vec<_>>();
+    let is_space = |c: char| ifs.contains(&c) && c.is_whitespace();
+    let mut rest = line
+        .clone()
+        .unwrap_or_default()
+        .trim_matches(is_space)
+        .to_string();
+    for (i, name) in names.iter().enumerate() {
+        if i + 1 == names.len() {
+            env.set_var(name, &rest);
+            break;
+        }
+        let (field, tail) = match rest.find(|c| ifs.contains(&c)) {
+            Some(pos) => {
+                let (field, tail) = rest.split_at(pos);
+                // whitespaces around a delimiter are skipped
+                let tail = tail.trim_start_matches(is_space);
+                let tail = match tail.chars().next() {
+                    Some(c) if ifs.contains(&c) && !c.is_whitespace() => {
+                        tail[c.len_utf8()..].trim_start_matches(is_space)
+                    }
+                    _ => tail,
+                };
+                (field.to_string(), tail.to_string())
+            }
+            None => (rest.clone(), String::new()),
+        };
+        env.set_var(name, &field);
+        rest = tail;
+    }
+    Ok(if line.is_some() { 0 } else { 1 })
+}
+
+// "local name[=value]...". the old values are restored when the function returns
+fn exec_local(command: Command, env: &mut Env) -> Result<i32, ExecutionError> {
+    if env.local_scopes.is_empty() {
+        return Err(ExecutionError::FunctionError(
+            "local".to_string(),
+            FunctionError::NotInFunction,
+        ));
+    }
+    for arg in command.str.iter().skip(1) {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        if !is_name(name) {
+            return Err(ExecutionError::VarError(
+                "local".to_string(),
+                VarError::InvalidName(arg.clone()),
+            ));
+        }
+        let old = (
+            name.to_string(),
+            env.get_var(name),
+            env::var_os(name).is_some(),
+        );
+        let scope = env.local_scopes.last_mut().unwrap();
+        if scope.iter().all(|(x, _, _)| x != name) {
+            scope.push(old);
+        }
+        match value {
+            Some(value) => env.set_var(name, value),
+            None => env.unset_var(name),
+        }
+    }
+    Ok(0)
+}
+
+fn exec_return(command: Command, env: &Env) -> Result<i32, ExecutionError> {
+    let error = |err| ExecutionError::FunctionError("return".to_string(), err);
+    if env.local_scopes.is_empty() {
+        return Err(error(FunctionError::NotInFunction));
+    }
+    match command.str.get(1) {
+        Some(arg) => match arg.parse() {
+            Ok(status) => Err(ExecutionError::Return(status)),
+            Err(_) => Err(error(FunctionError::InvalidStatus(arg.clone()))),
+        },
+        None => Err(ExecutionError::Return(env.last_status)),
+    }
+}
+
+// remembers the value of a variable and whether it is exported, to restore it later
+fn save_var(name: &str, saved: &mut Vec<(String, Option<String>, bool)>, env: &Env) {
+    if saved.iter().all(|(x, _, _)| x != name) {
+        saved.push((
+            name.to_string(),
+            env.get_var(name),
+            env::var_os(name).is_some(),
+        ));
+    }
+}
+
+// restores variables saved as (name, old value, whether it was exported), in reverse order
+fn restore_vars(saved: Vec<(String, Option<String>, bool)>, env: &mut Env) {
+    for (name, value, exported) in saved.into_iter().rev() {
+        match value {
+            Some(value) => {
+                env.set_var(&name, &value);
+                if exported {
+                    env.export_var(&name);
+                } else {
+                    env::remove_var(&name);
+                }
+            }
+            None => env.unset_var(&name),
+        }
+    }
+}
+
+// runs a function with its own positional parameters
+fn call_function(command: Command, body: Stage, env: &mut Env) -> Result<i32, ExecutionError> {
+    let params = std::mem::replace(&mut env.params, command.str[1..].to_vec());
+    env.local_scopes.push(Vec::new());
+    let res = execute_pipe_block(
+        PipeBlock {
+            command: body,
+            tail: None,
+            negated: false,
+            time: None,
+        },
+        env,
+    );
+    let scope = env.local_scopes.pop().unwrap();
+    restore_vars(scope, env);
+    env.params = params;
+    match res {
+        Err(ExecutionError::Return(status)) => Ok(status),
+        res => res,
+    }
+}
+
+// builtins which change the state of the shell are executed without fork
+const SHELL_BUILTINS: [&str; 9] = [
+    "cd", "set", "export", "unset", "read", "break", "continue", "local", "return",
+];
+
+fn exec_command_internal(mut command: Command, env: &mut Env) -> Result<i32, ExecutionError> {
+    if command.str.is_empty() {
+        // bare assignments set shell variables
+        for (name, value) in &command.assignments {
+            env.set_var(name, value);
+        }
+        // the status is that of the last command substitution as in bash
+        return Ok(if env.substituted { env.last_status } else { 0 });
+    }
+    let builtin = SHELL_BUILTINS.contains(&command.str[0].as_str())
+        || command.str[0] == "history"
+        || env.functions.contains_key(&command.str[0]);
+    if command.assignments.is_empty() || !builtin {
+        return dispatch_command(command, env);
+    }
+    // assignments before builtins and functions are exported only while they run
+    let mut saved = Vec::new();
+    for (name, value) in std::mem::take(&mut command.assignments) {
+        save_var(&name, &mut saved, env);
+        env.set_var(&name, &value);
+        env.export_var(&name);
+    }
+    let res = dispatch_command(command, env);
+    restore_vars(saved, env);
+    res
+}
+
+fn dispatch_command(command: Command, env: &mut Env) -> Result<i32, ExecutionError> {
     if command.str[0] == "cd" {
-        exec_cd(command)
+        exec_cd(command, env)
     } else if command.str[0] == "history" {
         exec_history(command, env)
+    } else if command.str[0] == "set" {
+        exec_set(command, env)
+    } else if command.str[0] == "export" {
+        exec_export(command, env)
+    } else if command.str[0] == "unset" {
+        exec_unset(command, env)
+    } else if command.str[0] == "read" {
+        exec_read(command, env)
+    } else if command.str[0] == "break" || command.str[0] == "continue" {
+        exec_break(command, env)
+    } else if command.str[0] == "local" {
+        exec_local(command, env)
+    } else if command.str[0] == "return" {
+        exec_return(command, env)
+    } else if let Some(body) = env.functions.get(&command.str[0]).cloned() {
+        // functions are looked up before PATH
+        call_function(command, body, env)
     } else {
-        exec_and_fork(command)
+        exec_and_fork(command, env)
+    }
+}
+
+// what is done to a file descriptor in the child process
+enum FdAction {
+    // a file opened by the shell, which is closed in the shell after fork
+    File(i32),
+    Dup(i32),
+    Close,
+}
+
+fn open_file(path: &str, flags: OFlag) -> Result<i32, String> {
+    let cstr = CString::new(path).unwrap();
+    let cstr = unsafe { CStr::from_bytes_with_nul_unchecked(cstr.to_bytes_with_nul()) };
+    use nix::sys::stat::Mode;
+    let fd = nix::fcntl::open(
+        cstr,
+        flags,
+        Mode::S_IRUSR
+            | Mode::S_IWUSR
+            | Mode::S_IRGRP
+            | Mode::S_IWGRP
+            | Mode::S_IROTH
+            | Mode::S_IWOTH,
+    )
+    .map_err(|err| format!("{}: {}", path, err))?;
+    move_fd(fd)
+}
+
+// with noclobber, existing regular files are not overwritten
+fn open_output(path: &str, noclobber: bool) -> Result<i32, String> {
+    if noclobber && std::fs::metadata(path).is_ok_and(|x| x.is_file()) {
+        return Err(format!("{}: cannot overwrite existing file", path));
+    }
+    open_file(path, OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_TRUNC)
+}
+
+// moves `fd` to a large fd so that it does not conflict with fds given by the user
+fn move_fd(fd: i32) -> Result<i32, String> {
+    let res = fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(10)).map_err(|err| err.to_string());
+    let _ = close(fd);
+    res
+}
+
+// the body of a here-document (or a here-string) is given through an unlinked temporary file
+fn open_heredoc(body: &str) -> Result<i32, String> {
+    let (fd, path) = mkstemp("/tmp/myshell-heredoc-XXXXXX").map_err(|err| err.to_string())?;
+    let _ = unlink(&path);
+    let mut buf = body.as_bytes();
+    while !buf.is_empty() {
+        match write(fd, buf) {
+            Ok(len) => buf = &buf[len..],
+            Err(err) => {
+                let _ = close(fd);
+                return Err(err.to_string());
+            }
+        }
+    }
+    if let Err(err) = lseek(fd, 0, Whence::SeekSet) {
+        let _ = close(fd);
+        return Err(err.to_string());
+    }
+    move_fd(fd)
+}
+
+fn close_files(actions: &[(i32, FdAction)]) {
+    for (_, action) in actions {
+        if let FdAction::File(fd) = action {
+            let _ = close(*fd);
+        }
+    }
+}
+
+fn open_redirects(
+    redirects: &[Redirect],
+    env: &Env,
+) -> Result<Vec<(i32, FdAction)>, ExecutionError> {
+    let mut res = Vec::new();
+    for redirect in redirects {
+        let action = match &redirect.kind {
+            RedirectKind::Input(path) => open_file(path, OFlag::O_RDONLY)
+                .map(FdAction::File)
+                .map_err(ExecutionError::InputRedirectError),
+            RedirectKind::Output(path) => open_output(path, env.noclobber)
+                .map(FdAction::File)
+                .map_err(ExecutionError::OutputRedirectError),
+            RedirectKind::Clobber(path) => open_output(path, false)
+                .map(FdAction::File)
+                .map_err(ExecutionError::OutputRedirectError),
+            RedirectKind::Append(path) => {
+                open_file(path, OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_APPEND)
+                    .map(FdAction::File)
+                    .map_err(ExecutionError::OutputRedirectError)
+            }
+            RedirectKind::ReadWrite(path) => open_file(path, OFlag::O_RDWR | OFlag::O_CREAT)
+                .map(FdAction::File)
+                .map_err(ExecutionError::InputRedirectError),
+            RedirectKind::HereDoc(doc) => open_heredoc(&doc.body)
+                .map(FdAction::File)
+                .map_err(ExecutionError::InputRedirectError),
+            RedirectKind::HereString(word) => open_heredoc(&(word.clone() + "\n"))
+                .map(FdAction::File)
+                .map_err(ExecutionError::InputRedirectError),
+            RedirectKind::DupInput(fd) | RedirectKind::DupOutput(fd) => Ok(FdAction::Dup(*fd)),
+            RedirectKind::CloseInput | RedirectKind::CloseOutput => Ok(FdAction::Close),
+        };
+        match action {
+            Ok(action) => res.push((redirect.fd, action)),
+            Err(err) => {
+                close_files(&res);
+                return Err(err);
+            }
+        }
+    }
+    Ok(res)
+}
+
+// expands a redirected file name, which must be one word
+fn expand_target(word: &str, env: &mut Env) -> Result<String, ExpansionError> {
+    let mut words = replace_tokens(&[word.to_string()], env)?;
+    if words.len() != 1 {
+        return Err(ExpansionError::AmbiguousRedirect(word.to_string()));
+    }
+    Ok(words.pop().unwrap())
+}
+
+fn expand_redirects(redirects: &mut [Redirect], env: &mut Env) -> Result<(), ExpansionError> {
+    for redirect in redirects.iter_mut() {
+        if let RedirectKind::HereDoc(doc) = &mut redirect.kind {
+            if doc.expand {
+                doc.body = expand_heredoc(&doc.body, env)?;
+                doc.expand = false;
+            }
+        } else if let RedirectKind::HereString(word) = &mut redirect.kind {
+            // the word is neither split nor globbed as in bash
+            *word = to_string(&expand_single(word, env)?);
+        } else if let Some(target) = redirect.kind.target_mut() {
+            *target = expand_target(target, env)?;
+        }
+    }
+    Ok(())
+}
+
+// words of a compound command are expanded when each command in it runs
+fn expand_stage(stage: Stage, env: &mut Env) -> Result<Stage, ExpansionError> {
+    match stage {
+        Stage::Simple(mut command) => {
+            env.substituted = false;
+            command.str = replace_tokens(&command.str, env)?;
+            expand_redirects(&mut command.redirects, env)?;
+            // each assignment is seen by the following ones. they are set only while they are
+            // expanded, and applied again when the command runs
+            let mut saved = Vec::new();
+            let res = command
+                .assignments
+                .iter_mut()
+                .try_for_each(|(name, value)| {
+                    *value = to_string(&expand_single(value, env)?);
+                    save_var(name, &mut saved, env);
+                    env.set_var(name, value);
+                    Ok(())
+                });
+            restore_vars(saved, env);
+            res.map(|_| Stage::Simple(command))
+        }
+        Stage::Compound(compound, mut redirects) => {
+            expand_redirects(&mut redirects, env)?;
+            Ok(Stage::Compound(compound, redirects))
+        }
+        Stage::Function(..) => Ok(stage),
     }
 }
 
+// called in the child process, from left to right
+fn apply_fd_actions(actions: &[(i32, FdAction)]) -> Result<(), ExecutionError> {
+    for (fd, action) in actions {
+        match action {
+            FdAction::File(src) | FdAction::Dup(src) => {
+                if let Err(err) = dup2(*src, *fd) {
+                    return Err(ExecutionError::DupError(format!(
+                        "{}>&{}: {}",
+                        fd, src, err
+                    )));
+                }
+            }
+            FdAction::Close => {
+                let _ = close(*fd);
+            }
+        }
+    }
+    Ok(())
+}
+
+// `next_fd` is the read end of the pipe from this stage, which is closed in the child so that
+// the stage gets SIGPIPE when the next one exits
+#[allow(clippy::too_many_arguments)]
 fn exec_command(
-    command: Command,
+    command: Stage,
     input_fd: i32,
     output_fd: i32,
-    err_fd: i32,
+    next_fd: i32,
+    actions: Vec<(i32, FdAction)>,
     is_tail: bool,
-    env: &Env,
+    children: &mut Vec<Pid>,
+    env: &mut Env,
 ) -> Result<Option<i32>, ExecutionError> {
-    if command.str[0] == "exit" {
-        return Err(ExecutionError::Exit);
-    }
-    if is_tail && command.str[0] == "cd" {
-        return Ok(Some(exec_cd(command)?));
+    if let Stage::Simple(command) = &command {
+        if command.str.first().is_some_and(|x| x == "exit") {
+            close_files(&actions);
+            // the pipes are closed so that the other stages can finish
+            for fd in [input_fd, output_fd] {
+                if fd > 2 {
+                    let _ = close(fd);
+                }
+            }
+            return Err(ExecutionError::Exit);
+        }
+        // builtins, functions and bare assignments at the tail run in the shell. the input from
+        // the pipe is given as a redirection
+        if is_tail
+            && command.str.first().is_none_or(|x| {
+                SHELL_BUILTINS.contains(&x.as_str()) || env.functions.contains_key(x)
+            })
+        {
+            let mut actions = actions;
+            if input_fd != 0 {
+                actions.insert(0, (0, FdAction::File(input_fd)));
+            }
+            let command = command.clone();
+            return exec_in_shell(actions, env, |env| exec_command_internal(command, env))
+                .map(Some);
+        }
     }
     match unsafe { fork() } {
         Ok(ForkResult::Parent { child }) => {
-            if is_tail {
-                match nix::sys::wait::waitpid(child, Some(WaitPidFlag::WCONTINUED)) {
-                    Ok(WaitStatus::Exited(_, status)) => Ok(Some(status)),
-                    Ok(WaitStatus::Signaled(_, nix::sys::signal::Signal::SIGINT, _)) => {
-                        Err(ExecutionError::InterruptError)
-                    }
-                    Ok(WaitStatus::Signaled(_, nix::sys::signal::Signal::SIGQUIT, _)) => {
-                        Err(ExecutionError::QuitError)
-                    }
-                    Err(err) => Err(ExecutionError::ExecError(err.to_string())),
-                    _ => Err(ExecutionError::ExecOtherError(command.to_string())),
-                }
-            } else {
-                if input_fd != 0 {
-                    if let Err(err) = close(input_fd) {
-                        return Err(ExecutionError::CloseError(err.to_string()));
-                    }
-                }
-                if output_fd != 1 {
-                    if let Err(err) = close(output_fd) {
-                        return Err(ExecutionError::CloseError(err.to_string()));
-                    }
+            close_files(&actions);
+            if input_fd != 0 {
+                if let Err(err) = close(input_fd) {
+                    return Err(ExecutionError::CloseError(err.to_string()));
                 }
-                if err_fd != 2 {
-                    if let Err(err) = close(err_fd) {
-                        return Err(ExecutionError::CloseError(err.to_string()));
-                    }
+            }
+            if output_fd != 1 {
+                if let Err(err) = close(output_fd) {
+                    return Err(ExecutionError::CloseError(err.to_string()));
                 }
+            }
+            if is_tail {
+                wait_child(child, &command, env).map(Some)
+            } else {
+                children.push(child);
                 Ok(None)
             }
         }
         Ok(ForkResult::Child) => {
+            if next_fd != 0 {
+                let _ = close(next_fd);
+            }
             if input_fd != 0 {
                 dup2(input_fd, 0).unwrap();
+                close(input_fd).unwrap();
             }
             if output_fd != 1 {
                 dup2(output_fd, 1).unwrap();
+                close(output_fd).unwrap();
             }
-            if err_fd != 2 {
-                dup2(err_fd, 2).unwrap();
-            }
-            if input_fd != 0 {
-                close(input_fd).unwrap();
+            if let Err(err) = apply_fd_actions(&actions) {
+                eprintln2!("{}", ErrorEnum::ExecutionError(err));
+                std::process::exit(1);
             }
-            if output_fd != 1 {
-                close(output_fd).unwrap();
+            // fds of process substitutions are inherited only by the command using their paths
+            for fd in &env.aux_fds {
+                let _ = fcntl(*fd, FcntlArg::F_SETFD(FdFlag::empty()));
             }
-            if err_fd != 2 {
-                close(err_fd).unwrap();
+            let command = match command {
+                Stage::Simple(command) => command,
+                Stage::Compound(compound, _) => {
+                    let res = execute_compound(compound, env);
+                    exit_subshell(res, env)
+                }
+                Stage::Function(..) => std::process::exit(0),
+            };
+            if command.str.is_empty() {
+                std::process::exit(0);
             }
-            match exec_command_internal(command, &env) {
+            match exec_command_internal(command, env) {
                 Ok(status) => {
                     std::process::exit(status);
                 }
                 Err(err) => {
-                    println2!("{}", ErrorEnum::ExecutionError(err));
+                    eprintln2!("{}", ErrorEnum::ExecutionError(err));
                     std::process::exit(-1);
                 }
             }
@@ -219,110 +884,420 @@ fn exec_command(
     }
 }
 
-fn execute_pipe_block(pipe_block: PipeBlock, env: &Env) -> Result<i32, ExecutionError> {
-    let mut input_fd = 0;
-    if let Some(path) = pipe_block.from {
-        let cstr = CString::new(path.clone()).unwrap();
-        let cstr = unsafe { CStr::from_bytes_with_nul_unchecked(cstr.to_bytes_with_nul()) };
-        match nix::fcntl::open(
-            cstr,
-            nix::fcntl::OFlag::O_RDONLY,
-            nix::sys::stat::Mode::all(),
-        ) {
-            Ok(fd) => input_fd = fd,
-            Err(err) => {
-                return Err(ExecutionError::InputRedirectError(err.to_string()));
-            }
-        }
-    }
-    let mut output_fd = 1;
-    if let Some(path) = pipe_block.to {
-        let cstr = CString::new(path.clone()).unwrap();
-        let cstr = unsafe { CStr::from_bytes_with_nul_unchecked(cstr.to_bytes_with_nul()) };
-        use nix::sys::stat::Mode;
-        match nix::fcntl::open(
-            cstr,
-            nix::fcntl::OFlag::O_WRONLY | nix::fcntl::OFlag::O_CREAT,
-            Mode::S_IRUSR
-                | Mode::S_IWUSR
-                | Mode::S_IRGRP
-                | Mode::S_IWGRP
-                | Mode::S_IROTH
-                | Mode::S_IWOTH,
-        ) {
-            Ok(fd) => output_fd = fd,
-            Err(err) => {
-                return Err(ExecutionError::OutputRedirectError(err.to_string()));
-            }
-        }
-    }
-    let mut err_fd = 2;
-    if let Some(path) = pipe_block.to_err {
-        let cstr = CString::new(path.clone()).unwrap();
-        let cstr = unsafe { CStr::from_bytes_with_nul_unchecked(cstr.to_bytes_with_nul()) };
-        use nix::sys::stat::Mode;
-        match nix::fcntl::open(
-            cstr,
-            nix::fcntl::OFlag::O_WRONLY | nix::fcntl::OFlag::O_CREAT,
-            Mode::S_IRUSR
-                | Mode::S_IWUSR
-                | Mode::S_IRGRP
-                | Mode::S_IWGRP
-                | Mode::S_IROTH
-                | Mode::S_IWOTH,
-        ) {
-            Ok(fd) => err_fd = fd,
-            Err(err) => {
-                return Err(ExecutionError::OutputRedirectError(err.to_string()));
+// exits from a forked process with the status of the commands run in it
+pub fn exit_subshell(res: Result<i32, ExecutionError>, env: &Env) -> ! {
+    let status = match res {
+        Ok(status) => status,
+        Err(
+            ExecutionError::Exit
+            | ExecutionError::StatementIsEmpty
+            | ExecutionError::Break(_)
+            | ExecutionError::Continue(_),
+        ) => env.last_status,
+        Err(ExecutionError::Return(status)) => status,
+        Err(err) => {
+            eprintln2!("{}", ErrorEnum::ExecutionError(err));
+            1
+        }
+    };
+    std::process::exit(status);
+}
+
+// the status of a command. errors like a failed redirection only fail the command, but
+// the exit, interrupts and "break" are passed through
+fn command_status(res: Result<i32, ExecutionError>) -> Result<i32, ExecutionError> {
+    match res {
+        Ok(status) => Ok(status),
+        Err(err) if err.is_control() => Err(err),
+        Err(err) => {
+            eprintln2!("{}", ErrorEnum::ExecutionError(err));
+            Ok(1)
+        }
+    }
+}
+
+// what a loop does next
+enum Flow {
+    Normal(i32),
+    Break,
+    Continue,
+}
+
+// catches "break" and "continue" for the innermost loop
+fn loop_flow(res: Result<i32, ExecutionError>) -> Result<Flow, ExecutionError> {
+    match res {
+        Ok(status) => Ok(Flow::Normal(status)),
+        Err(ExecutionError::Break(1)) => Ok(Flow::Break),
+        Err(ExecutionError::Break(n)) => Err(ExecutionError::Break(n - 1)),
+        Err(ExecutionError::Continue(1)) => Ok(Flow::Continue),
+        Err(ExecutionError::Continue(n)) => Err(ExecutionError::Continue(n - 1)),
+        Err(err) => Err(err),
+    }
+}
+
+// ctrl-c stops the whole loop, even if the shell receives it between commands
+fn check_interrupted() -> Result<(), ExecutionError> {
+    if INTERRUPTED.swap(false, Ordering::SeqCst) {
+        Err(ExecutionError::InterruptError)
+    } else {
+        Ok(())
+    }
+}
+
+// runs the body of a loop, and returns false if the loop is broken
+fn execute_body(body: &Statement, status: &mut i32, env: &mut Env) -> Result<bool, ExecutionError> {
+    match loop_flow(execute(body.clone(), env))? {
+        Flow::Normal(x) => *status = x,
+        Flow::Continue => *status = 0,
+        Flow::Break => {
+            *status = 0;
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn execute_while(
+    cond: Statement,
+    body: Statement,
+    until: bool,
+    env: &mut Env,
+) -> Result<i32, ExecutionError> {
+    let mut status = 0;
+    loop {
+        check_interrupted()?;
+        match loop_flow(execute(cond.clone(), env))? {
+            Flow::Normal(x) if (x == 0) != until => {}
+            Flow::Continue => continue,
+            _ => break,
+        }
+        if !execute_body(&body, &mut status, env)? {
+            break;
+        }
+    }
+    Ok(status)
+}
+
+fn execute_for(
+    name: String,
+    words: Option<Vec<String>>,
+    body: Statement,
+    env: &mut Env,
+) -> Result<i32, ExecutionError> {
+    let words = match words {
+        Some(words) => replace_tokens(&words, env).map_err(ExecutionError::ExpansionError)?,
+        None => env.params.clone(),
+    };
+    let mut status = 0;
+    for word in words {
+        check_interrupted()?;
+        env.set_var(&name, &word);
+        if !execute_body(&body, &mut status, env)? {
+            break;
+        }
+    }
+    Ok(status)
+}
+
+// "for ((init; cond; update))". an empty condition is true
+fn execute_arith_for(
+    exprs: [String; 3],
+    body: Statement,
+    env: &mut Env,
+) -> Result<i32, ExecutionError> {
+    let [init, cond, update] = exprs;
+    let eval = |expr: &str, env: &mut Env| {
+        expand_arith_expr(expr, env).map_err(ExecutionError::ExpansionError)
+    };
+    let mut status = 0;
+    eval(&init, env)?;
+    loop {
+        check_interrupted()?;
+        if !cond.is_empty() && eval(&cond, env)? == 0 {
+            break;
+        }
+        if !execute_body(&body, &mut status, env)? {
+            break;
+        }
+        eval(&update, env)?;
+    }
+    Ok(status)
+}
+
+// the body of the first item whose pattern matches the word is executed. ";&" falls through to
+// the next body, and ";;&" continues to test the following patterns
+fn execute_case(
+    word: String,
+    items: Vec<(Vec<String>, Statement, Operator)>,
+    env: &mut Env,
+) -> Result<i32, ExecutionError> {
+    let word = expand_single(&word, env).map_err(ExecutionError::ExpansionError)?;
+    let word = word.into_iter().map(|(c, _)| c).collect::<Vec<_>>();
+    let mut status = 0;
+    let mut fall_through = false;
+    for (patterns, body, end) in items {
+        if !fall_through {
+            let mut matched = false;
+            for pattern in patterns {
+                let pattern =
+                    expand_single(&pattern, env).map_err(ExecutionError::ExpansionError)?;
+                if matches(&pattern, &word) {
+                    matched = true;
+                    break;
+                }
+            }
+            if !matched {
+                continue;
             }
         }
+        status = if body.stmt.is_empty() {
+            0
+        } else {
+            execute(body, env)?
+        };
+        match end {
+            Operator::SemiColonAnd => fall_through = true,
+            Operator::DoubleSemiColonAnd => fall_through = false,
+            _ => break,
+        }
     }
+    Ok(status)
+}
 
-    let mut command_vec = Vec::new();
+// runs a loop, counting the depth of loops for "break" and "continue"
+fn execute_loop(
+    env: &mut Env,
+    f: impl FnOnce(&mut Env) -> Result<i32, ExecutionError>,
+) -> Result<i32, ExecutionError> {
+    if env.loop_depth == 0 {
+        INTERRUPTED.store(false, Ordering::SeqCst);
+    }
+    env.loop_depth += 1;
+    let res = f(env);
+    env.loop_depth -= 1;
+    res
+}
 
-    let mut now_out_fd = output_fd;
-    let mut nex_in_fd = 0;
-    (now_out_fd, nex_in_fd) = match pipe_block.tail {
-        None => Ok((output_fd, 0)),
-        Some(_) => match pipe() {
-            Ok((read_pipe, write_pipe)) => Ok((write_pipe, read_pipe)),
-            Err(err) => Err(ExecutionError::PipeError(err.to_string())),
-        },
-    }?;
-    command_vec.push((pipe_block.command, input_fd, now_out_fd));
+// runs a compound command in the current process
+fn execute_compound(compound: Compound, env: &mut Env) -> Result<i32, ExecutionError> {
+    match compound {
+        Compound::Subshell(stmt) | Compound::Group(stmt) => execute(stmt, env),
+        Compound::If(branches, els) => {
+            for (cond, body) in branches {
+                if execute(cond, env)? == 0 {
+                    return execute(body, env);
+                }
+            }
+            match els {
+                Some(els) => execute(els, env),
+                None => Ok(0),
+            }
+        }
+        Compound::While(cond, body) => {
+            execute_loop(env, |env| execute_while(cond, body, false, env))
+        }
+        Compound::Until(cond, body) => {
+            execute_loop(env, |env| execute_while(cond, body, true, env))
+        }
+        Compound::Case(word, items) => execute_case(word, items, env),
+        Compound::For(name, words, body) => {
+            execute_loop(env, |env| execute_for(name, words, body, env))
+        }
+        Compound::ArithFor(init, cond, update, body) => execute_loop(env, |env| {
+            execute_arith_for([init, cond, update], body, env)
+        }),
+    }
+}
+
+fn flush_std() {
+    let _ = std::io::stdout().flush();
+    let _ = std::io::stderr().flush();
+}
+
+// applies redirections in the shell to run `f`, and restores the fds after it
+fn exec_in_shell(
+    actions: Vec<(i32, FdAction)>,
+    env: &mut Env,
+    f: impl FnOnce(&mut Env) -> Result<i32, ExecutionError>,
+) -> Result<i32, ExecutionError> {
+    let mut saved = Vec::new();
+    for (fd, _) in &actions {
+        if saved.iter().all(|(x, _)| x != fd) {
+            // None if the fd is not open
+            saved.push((*fd, fcntl(*fd, FcntlArg::F_DUPFD_CLOEXEC(10)).ok()));
+        }
+    }
+    // buffered output is written to the fds it was printed for
+    flush_std();
+    let res = apply_fd_actions(&actions).and_then(|_| f(env));
+    flush_std();
+    close_files(&actions);
+    for (fd, copy) in saved {
+        match copy {
+            Some(copy) => {
+                let _ = dup2(copy, fd);
+                let _ = close(copy);
+            }
+            None => {
+                let _ = close(fd);
+            }
+        }
+    }
+    res
+}
+
+// closes fds of process substitutions after the command using them has started
+fn close_aux_fds(env: &mut Env) {
+    for fd in env.aux_fds.drain(..) {
+        let _ = close(fd);
+    }
+}
+
+// reaps children of process substitutions which have finished
+fn reap_aux_children(env: &mut Env) {
+    env.aux_children.retain(|&pid| {
+        matches!(
+            nix::sys::wait::waitpid(Pid::from_raw(pid), Some(WaitPidFlag::WNOHANG)),
+            Ok(WaitStatus::StillAlive)
+        )
+    });
+}
+
+// runs a pipeline with "time" and "!"
+fn execute_pipe_block(pipe_block: PipeBlock, env: &mut Env) -> Result<i32, ExecutionError> {
+    let negated = pipe_block.negated;
+    let res = match pipe_block.time {
+        Some(format) => {
+            let start = Instant::now();
+            let self_start = time::self_usage();
+            let child_start = env.child_usage;
+            let res = execute_pipeline(pipe_block, env);
+            let mut usage = time::self_usage().since(self_start);
+            usage.add(env.child_usage.since(child_start));
+            let format = match format {
+                TimeFormat::Default => env
+                    .get_var("TIMEFORMAT")
+                    .unwrap_or_else(|| time::DEFAULT_FORMAT.to_string()),
+                TimeFormat::Posix => time::POSIX_FORMAT.to_string(),
+            };
+            // an empty TIMEFORMAT prints nothing
+            if !format.is_empty() {
+                eprintln2!("{}", time::format_times(&format, start.elapsed(), usage));
+            }
+            res
+        }
+        None => execute_pipeline(pipe_block, env),
+    };
+    let res = command_status(res);
+    if negated {
+        res.map(|status| (status == 0) as i32)
+    } else {
+        res
+    }
+}
 
+fn execute_pipeline(pipe_block: PipeBlock, env: &mut Env) -> Result<i32, ExecutionError> {
+    let mut command_vec = vec![pipe_block.command];
     let mut tail = pipe_block.tail;
-    while tail.is_some() {
-        let mut pipe_node = tail.unwrap();
-        let now_in_fd = nex_in_fd;
-        (now_out_fd, nex_in_fd) = match pipe_node.tail {
-            None => Ok((output_fd, 0)),
-            Some(_) => match pipe() {
-                Ok((read_pipe, write_pipe)) => Ok((write_pipe, read_pipe)),
-                Err(err) => Err(ExecutionError::PipeError(err.to_string())),
-            },
-        }?;
-        command_vec.push((pipe_node.command, now_in_fd, now_out_fd));
+    while let Some(pipe_node) = tail {
+        command_vec.push(pipe_node.command);
         tail = pipe_node.tail.map(|x| *x);
     }
 
     let mut res = None;
-    for (i, (command, input_fd, output_fd)) in command_vec.iter().cloned().enumerate() {
-        res = exec_command(
-            command,
-            input_fd,
-            output_fd,
-            err_fd,
-            i + 1 == command_vec.len(),
-            env,
-        )?;
+    let mut input_fd = 0;
+    let mut children = Vec::new();
+    let n = command_vec.len();
+    for (i, command) in command_vec.into_iter().enumerate() {
+        let is_tail = i + 1 == n;
+        let (output_fd, nex_in_fd) = if is_tail {
+            (1, 0)
+        } else {
+            match pipe() {
+                Ok((read_pipe, write_pipe)) => (write_pipe, read_pipe),
+                Err(err) => {
+                    if input_fd != 0 {
+                        let _ = close(input_fd);
+                    }
+                    wait_stages(children, env);
+                    return Err(ExecutionError::PipeError(err.to_string()));
+                }
+            }
+        };
+        // words are expanded just before the command runs, and redirections are applied
+        // after the pipe
+        let opened = expand_stage(command, env)
+            .map_err(ExecutionError::ExpansionError)
+            .and_then(|command| {
+                let redirects = match &command {
+                    Stage::Simple(command) => &command.redirects[..],
+                    Stage::Compound(_, redirects) => &redirects[..],
+                    Stage::Function(..) => &[],
+                };
+                Ok((open_redirects(redirects, env)?, command))
+            });
+        let (actions, command) = match opened {
+            Ok(opened) => opened,
+            Err(err) => {
+                for fd in [input_fd, output_fd, nex_in_fd] {
+                    if fd > 2 {
+                        let _ = close(fd);
+                    }
+                }
+                close_aux_fds(env);
+                wait_stages(children, env);
+                return Err(err);
+            }
+        };
+        let status = match command {
+            // compound commands except subshells run in the shell unless they are in a pipeline
+            Stage::Compound(compound, _)
+                if n == 1 && !matches!(compound, Compound::Subshell(_)) =>
+            {
+                exec_in_shell(actions, env, |env| execute_compound(compound, env)).map(Some)
+            }
+            Stage::Function(name, body) if n == 1 => {
+                env.functions.insert(name, *body);
+                Ok(Some(0))
+            }
+            command => exec_command(
+                command,
+                input_fd,
+                output_fd,
+                nex_in_fd,
+                actions,
+                is_tail,
+                &mut children,
+                env,
+            ),
+        };
+        close_aux_fds(env);
+        res = match status {
+            Ok(status) => status,
+            Err(err) => {
+                if nex_in_fd != 0 {
+                    let _ = close(nex_in_fd);
+                }
+                wait_stages(children, env);
+                return Err(err);
+            }
+        };
+        input_fd = nex_in_fd;
     }
+    wait_stages(children, env);
+    reap_aux_children(env);
     Ok(res.unwrap())
 }
 
-fn execute_commands(commands: Commands, env: &Env) -> Result<i32, ExecutionError> {
+fn execute_commands(commands: Commands, env: &mut Env) -> Result<i32, ExecutionError> {
     let head_result = execute_pipe_block(commands.head, env);
-    let success = head_result.clone().map_or(false, |x| x == 0);
+    if let Ok(status) = head_result {
+        env.last_status = status;
+    }
+    if head_result.as_ref().is_err_and(|err| err.is_control()) {
+        return head_result;
+    }
+    let success = head_result.clone().is_ok_and(|x| x == 0);
     match commands.tail {
         None => head_result,
         Some((op, tail)) => match op {
@@ -345,9 +1320,12 @@ fn execute_commands(commands: Commands, env: &Env) -> Result<i32, ExecutionError
     }
 }
 
-fn execute_commands_background(commands: Commands, env: &Env) -> Result<i32, ExecutionError> {
+fn execute_commands_background(commands: Commands, env: &mut Env) -> Result<i32, ExecutionError> {
     match unsafe { fork() } {
-        Ok(ForkResult::Parent { child: _ }) => Ok(0),
+        Ok(ForkResult::Parent { child }) => {
+            env.last_bg_pid = Some(child.as_raw());
+            Ok(0)
+        }
         Ok(ForkResult::Child) => {
             let pid = std::process::id();
             println2!(
@@ -364,28 +1342,84 @@ fn execute_commands_background(commands: Commands, env: &Env) -> Result<i32, Exe
                 }
                 Err(err) => {
                     println2!();
-                    println2!("process {} raises an error: {}", pid, err.to_string());
+                    println2!("process {} raises an error: {}", pid, err);
                     std::process::exit(-1);
                 }
             }
-            unreachable!()
         }
         Err(err) => Err(ExecutionError::ForkError(err.to_string())),
     }
 }
 
-pub fn execute(stmt: Statement, env: &Env) -> Result<i32, ExecutionError> {
+pub fn execute(stmt: Statement, env: &mut Env) -> Result<i32, ExecutionError> {
     let mut res = None;
     if stmt.stmt.is_empty() {
         Err(ExecutionError::StatementIsEmpty)
     } else {
         for (b, background) in stmt.stmt {
             if background {
-                res = Some(execute_commands_background(b, &env)?);
+                res = Some(execute_commands_background(b, env)?);
             } else {
-                res = Some(execute_commands(b, &env)?);
+                res = Some(execute_commands(b, env)?);
             }
         }
         Ok(res.unwrap())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(s: &str, env: &mut Env) -> i32 {
+        execute(make_parse_tree_from_str(s).unwrap(), env).unwrap()
+    }
+
+    #[test]
+    fn assignments_are_applied_from_left_to_right() {
+        let mut env = Env::for_test();
+        run("ex_a=1 ex_b=$ex_a", &mut env);
+        assert_eq!(env.get_var("ex_b").as_deref(), Some("1"));
+        // the statuses show the variables seen by the commands
+        assert_eq!(
+            run(
+                "ex_c=2 ex_d=$ex_c$ex_a /bin/sh -c 'exit $ex_c$ex_d'",
+                &mut env
+            ),
+            221
+        );
+        // the assignments are only for the command
+        assert_eq!(env.get_var("ex_c"), None);
+        assert_eq!(env.get_var("ex_d"), None);
+        // words of the command see the old values
+        assert_eq!(run("ex_a=3 /bin/sh -c 'exit $0$ex_a' $ex_a", &mut env), 13);
+        assert_eq!(
+            run(
+                "ex_a=4 ex_c=$ex_a ex_d=${ex_c} /bin/sh -c 'exit $ex_c$ex_d'",
+                &mut env
+            ),
+            44
+        );
+        assert_eq!(env.get_var("ex_a").as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn here_strings_are_one_word() {
+        let mut env = Env::for_test();
+        env.set_var("ex_words", "a  b");
+        let mut redirects = vec![Redirect {
+            fd: 0,
+            kind: RedirectKind::HereString("$ex_words*".to_string()),
+        }];
+        expand_redirects(&mut redirects, &mut env).unwrap();
+        assert!(matches!(&redirects[0].kind, RedirectKind::HereString(word) if word == "a  b*"));
+    }
+
+    #[test]
+    fn bare_assignments_return_the_status_of_command_substitutions() {
+        let mut env = Env::for_test();
+        assert_eq!(run("ex_s=$(/bin/false)", &mut env), 1);
+        assert_eq!(run("ex_s=$(/bin/false) ex_t=$(/bin/true)", &mut env), 0);
+        assert_eq!(run("/bin/false; ex_s=1", &mut env), 0);
+    }
+}
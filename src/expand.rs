@@ -0,0 +1,995 @@
+use crate::arith::{evaluate, ArithError};
+use crate::execute::{execute, exit_subshell};
+use crate::lexer::{
+    skip_backquote, skip_command, skip_double_quote, skip_param, skip_single_quote,
+};
+use crate::parser::{make_parse_tree_from_str, Statement};
+use crate::time::wait4;
+use crate::utils::{is_name, Env};
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg};
+use nix::sys::wait::WaitStatus;
+use nix::unistd::{close, dup2, fork, pipe, read, ForkResult, Pid, User};
+use thiserror::Error;
+
+#[derive(Clone, Error, Debug)]
+pub enum ExpansionError {
+    #[error("{0}: {1}")]
+    NullOrUnset(String, String),
+    #[error("{0}: bad substitution")]
+    BadSubstitution(String),
+    #[error("{0}: cannot assign in this way")]
+    CannotAssign(String),
+    #[error("command substitution: {0}")]
+    CommandSubstitution(String),
+    #[error("process substitution: {0}")]
+    ProcessSubstitution(String),
+    #[error("{0}: {1}")]
+    ArithError(String, ArithError),
+    #[error("no match: {0}")]
+    NoMatch(String),
+    #[error("{0}: ambiguous redirect")]
+    AmbiguousRedirect(String),
+}
+
+// a char of an expanded word and whether it is quoted
+pub type ExpChar = (char, bool);
+
+pub fn to_string(chars: &[ExpChar]) -> String {
+    chars.iter().map(|&(c, _)| c).collect()
+}
+
+// the result of expanding a word. "$@" in double quotes splits it into multiple fields
+pub type Fields = Vec<Vec<ExpChar>>;
+
+fn join_fields(fields: Fields) -> Vec<ExpChar> {
+    let mut res = Vec::new();
+    for (i, field) in fields.into_iter().enumerate() {
+        if i != 0 {
+            res.push((' ', true));
+        }
+        res.extend(field);
+    }
+    res
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// the length of the parameter name at the head of `s`. positional parameters after '$'
+// are one digit, and those in "${...}" may have more digits
+fn param_len(s: &[char], braced: bool) -> usize {
+    match s.first() {
+        Some('?' | '$' | '!' | '#' | '@' | '*') => 1,
+        Some(c) if c.is_ascii_digit() && braced => {
+            s.iter().take_while(|c| c.is_ascii_digit()).count()
+        }
+        Some(c) if c.is_ascii_digit() => 1,
+        _ => s.iter().take_while(|&&c| is_name_char(c)).count(),
+    }
+}
+
+// the value of a parameter. "$@" and "$*" are joined into one string
+fn get_param(name: &str, env: &Env) -> Option<String> {
+    match name {
+        "?" => Some(env.last_status.to_string()),
+        "$" => Some(env.shell_pid.to_string()),
+        "!" => env.last_bg_pid.map(|x| x.to_string()),
+        "#" => Some(env.params.len().to_string()),
+        "0" => Some(env.shell_name.clone()),
+        "@" | "*" if env.params.is_empty() => None,
+        "@" => Some(env.params.join(" ")),
+        "*" => {
+            // joined with the first char of IFS
+            let sep = env.get_var("IFS").map_or(" ".to_string(), |x| {
+                x.chars().next().map(String::from).unwrap_or_default()
+            });
+            Some(env.params.join(&sep))
+        }
+        _ if name.starts_with(|c: char| c.is_ascii_digit()) => name
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| env.params.get(i - 1).cloned()),
+        _ => env.get_var(name),
+    }
+}
+
+fn quote_all(s: &str, quoted: bool) -> Vec<ExpChar> {
+    s.chars().map(|c| (c, quoted)).collect()
+}
+
+// "$@" in double quotes, and "$@" and "$*" without quotes become a field for each positional
+// parameter
+fn expand_param(name: &str, env: &Env, quoted: bool) -> Fields {
+    if name == "@" || name == "*" && !quoted {
+        env.params.iter().map(|x| quote_all(x, quoted)).collect()
+    } else {
+        vec![quote_all(&get_param(name, env).unwrap_or_default(), quoted)]
+    }
+}
+
+// expands "${...}" whose inside is `s`
+fn expand_braced(s: &[char], env: &mut Env, quoted: bool) -> Result<Fields, ExpansionError> {
+    let bad_substitution =
+        || ExpansionError::BadSubstitution(format!("${{{}}}", s.iter().collect::<String>()));
+    let len = param_len(s, true);
+    if len == 0 {
+        return Err(bad_substitution());
+    }
+    let name = s[..len].iter().collect::<String>();
+    if len == s.len() {
+        return Ok(expand_param(&name, env, quoted));
+    }
+    let value = get_param(&name, env);
+    // with ':', an empty value is treated as unset
+    let colon = s[len] == ':';
+    let op_pos = len + colon as usize;
+    let op = match s.get(op_pos) {
+        Some(&op) if ['-', '=', '?', '+'].contains(&op) => op,
+        _ => return Err(bad_substitution()),
+    };
+    let word = &s[op_pos + 1..];
+    let is_set = value.as_ref().is_some_and(|x| !colon || !x.is_empty());
+    let context = if quoted {
+        Context::DoubleQuote
+    } else {
+        Context::Word
+    };
+    let expand_arg = |env: &mut Env| expand_chars(word, env, context);
+    match op {
+        '-' | '=' | '?' if is_set => Ok(expand_param(&name, env, quoted)),
+        '-' => expand_arg(env),
+        '=' if !is_name(&name) => Err(ExpansionError::CannotAssign(name)),
+        '=' => {
+            let value = to_string(&join_fields(expand_arg(env)?));
+            env.set_var(&name, &value);
+            Ok(vec![quote_all(&value, quoted)])
+        }
+        '?' => {
+            let message = to_string(&join_fields(expand_arg(env)?));
+            Err(ExpansionError::NullOrUnset(
+                name,
+                if message.is_empty() {
+                    "parameter null or not set".to_string()
+                } else {
+                    message
+                },
+            ))
+        }
+        '+' if is_set => expand_arg(env),
+        _ => Ok(vec![Vec::new()]),
+    }
+}
+
+// the directory of a tilde-prefix "~name": the home of the user, or $PWD and $OLDPWD for "~+"
+// and "~-"
+pub fn expand_tilde(name: &str, env: &Env) -> Option<String> {
+    match name {
+        "" => Some(
+            env.get_var("HOME")
+                .unwrap_or_else(|| env.home_dir.display().to_string()),
+        ),
+        "+" => env.get_var("PWD"),
+        "-" => env.get_var("OLDPWD"),
+        _ => User::from_name(name)
+            .ok()
+            .flatten()
+            .map(|user| user.dir.display().to_string()),
+    }
+}
+
+// runs `stmt` in a forked child whose `target` (stdin or stdout) is `fd`. `unused` is the other
+// end of the pipe, which is closed in the child
+fn spawn_subshell(
+    stmt: Statement,
+    env: &mut Env,
+    fd: i32,
+    target: i32,
+    unused: i32,
+) -> nix::Result<Pid> {
+    match unsafe { fork() }? {
+        ForkResult::Parent { child } => Ok(child),
+        ForkResult::Child => {
+            let _ = close(unused);
+            if fd != target {
+                let _ = dup2(fd, target);
+                let _ = close(fd);
+            }
+            let res = execute(stmt, env);
+            exit_subshell(res, env)
+        }
+    }
+}
+
+// runs `cmd` in a subshell, and returns its output without trailing newlines
+fn command_substitution(cmd: &str, env: &mut Env) -> Result<String, ExpansionError> {
+    let error = |err: String| ExpansionError::CommandSubstitution(err);
+    let stmt = make_parse_tree_from_str(cmd).map_err(|err| error(err.to_string()))?;
+    let (read_fd, write_fd) = pipe().map_err(|err| error(err.to_string()))?;
+    let child = spawn_subshell(stmt, env, write_fd, 1, read_fd);
+    let _ = close(write_fd);
+    let child = match child {
+        Ok(child) => child,
+        Err(err) => {
+            let _ = close(read_fd);
+            return Err(error(err.to_string()));
+        }
+    };
+    let mut output = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        match read(read_fd, &mut buf) {
+            Ok(0) => break,
+            Ok(len) => output.extend_from_slice(&buf[..len]),
+            Err(Errno::EINTR) => continue,
+            Err(_) => break,
+        }
+    }
+    let _ = close(read_fd);
+    env.substituted = true;
+    env.last_status = match wait4(child, None) {
+        Ok((status, usage)) => {
+            env.child_usage.add(usage);
+            match status {
+                WaitStatus::Exited(_, status) => status,
+                WaitStatus::Signaled(_, signal, _) => 128 + signal as i32,
+                _ => -1,
+            }
+        }
+        Err(_) => -1,
+    };
+    let output = String::from_utf8_lossy(&output);
+    Ok(output.trim_end_matches('\n').to_string())
+}
+
+// runs `cmd` connected by a pipe, and returns the path of the end kept in the shell.
+// the fd is closed and the child is reaped by the executor after the command starts
+fn process_substitution(cmd: &str, output: bool, env: &mut Env) -> Result<String, ExpansionError> {
+    let error = |err: String| ExpansionError::ProcessSubstitution(err);
+    let stmt = make_parse_tree_from_str(cmd).map_err(|err| error(err.to_string()))?;
+    let (read_fd, write_fd) = pipe().map_err(|err| error(err.to_string()))?;
+    // the shell keeps the write end for ">(cmd)" and the read end for "<(cmd)"
+    let (fd, child_fd, target) = if output {
+        (write_fd, read_fd, 0)
+    } else {
+        (read_fd, write_fd, 1)
+    };
+    let child = spawn_subshell(stmt, env, child_fd, target, fd);
+    let _ = close(child_fd);
+    let child = match child {
+        Ok(child) => child,
+        Err(err) => {
+            let _ = close(fd);
+            return Err(error(err.to_string()));
+        }
+    };
+    env.aux_children.push(child.as_raw());
+    // moved to a large fd, which is close-on-exec until the command using it is started
+    let moved = fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(10));
+    let _ = close(fd);
+    let fd = moved.map_err(|err| error(err.to_string()))?;
+    env.aux_fds.push(fd);
+    Ok(format!("/dev/fd/{}", fd))
+}
+
+// backslashes in "`...`" only escape '$', '`' and '\\'
+fn unescape_backquote(s: &[char]) -> String {
+    let mut res = String::new();
+    let mut i = 0;
+    while i < s.len() {
+        if s[i] == '\\' && i + 1 < s.len() && ['$', '`', '\\'].contains(&s[i + 1]) {
+            i += 1;
+        }
+        res.push(s[i]);
+        i += 1;
+    }
+    res
+}
+
+// expands "$..." at the head of `s`, and returns the result and the consumed length
+// parameters and commands in the expression are expanded before it is evaluated
+fn expand_arith(s: &[char], env: &mut Env) -> Result<i64, ExpansionError> {
+    let expr = to_string(&join_fields(expand_chars(s, env, Context::HereDoc)?));
+    evaluate(&expr, env).map_err(|err| ExpansionError::ArithError(expr.trim().to_string(), err))
+}
+
+fn expand_dollar(
+    s: &[char],
+    env: &mut Env,
+    quoted: bool,
+) -> Result<(Fields, usize), ExpansionError> {
+    if s.get(1) == Some(&'{') {
+        if let Ok(end) = skip_param(s, 0) {
+            return Ok((expand_braced(&s[2..end - 1], env, quoted)?, end));
+        }
+    }
+    if s.get(1) == Some(&'(') {
+        if let Ok(end) = skip_command(s, 0) {
+            // "$((...))" is an arithmetic expansion if it ends with "))"
+            if s[2] == '(' && s[end - 2] == ')' {
+                let value = expand_arith(&s[3..end - 2], env)?;
+                return Ok((vec![quote_all(&value.to_string(), quoted)], end));
+            }
+            let cmd = s[2..end - 1].iter().collect::<String>();
+            return Ok((
+                vec![quote_all(&command_substitution(&cmd, env)?, quoted)],
+                end,
+            ));
+        }
+    }
+    let len = param_len(&s[1..], false);
+    if len == 0 {
+        // not an expansion
+        return Ok((vec![vec![('$', quoted)]], 1));
+    }
+    let name = s[1..len + 1].iter().collect::<String>();
+    Ok((expand_param(&name, env, quoted), len + 1))
+}
+
+// where a word is expanded
+#[derive(Clone, Copy, PartialEq)]
+enum Context {
+    Word,
+    // a word which is not split into fields, like the word and the patterns of "case"
+    Single,
+    // the word of "${x:-word}" in double quotes
+    DoubleQuote,
+    // quotes are not special, and backslashes only escape '$', '`', '\\' and newlines
+    HereDoc,
+}
+
+// builds fields of a word. unquoted results of expansions are split by IFS
+struct FieldBuilder {
+    fields: Fields,
+    // empty fields are removed unless they are quoted or delimited by non-whitespace IFS chars
+    kept: Vec<bool>,
+    // the next char starts a new field
+    split: bool,
+    // the current field is ended by a non-whitespace IFS char
+    delimited: bool,
+    ifs: Vec<char>,
+}
+
+impl FieldBuilder {
+    fn new(env: &Env, split: bool) -> FieldBuilder {
+        let ifs = if split {
+            env.get_var("IFS")
+                .unwrap_or_else(|| " \t\n".to_string())
+                .chars()
+                .collect()
+        } else {
+            Vec::new()
+        };
+        FieldBuilder {
+            fields: vec![Vec::new()],
+            kept: vec![false],
+            split: false,
+            delimited: false,
+            ifs,
+        }
+    }
+    fn current(&mut self) -> &mut Vec<ExpChar> {
+        if self.split {
+            self.fields.push(Vec::new());
+            self.kept.push(false);
+            self.split = false;
+            self.delimited = false;
+        }
+        self.fields.last_mut().unwrap()
+    }
+    fn push(&mut self, c: ExpChar) {
+        self.current().push(c);
+    }
+    fn extend(&mut self, chars: Vec<ExpChar>) {
+        self.current().extend(chars);
+    }
+    // the current field remains even if it is empty
+    fn keep(&mut self) {
+        self.current();
+        *self.kept.last_mut().unwrap() = true;
+    }
+    // ends the current field unless it is empty
+    fn separate(&mut self) {
+        if !self.split && (*self.kept.last().unwrap() || !self.fields.last().unwrap().is_empty()) {
+            self.split = true;
+        }
+    }
+    // appends the result of an expansion. each field after the first one starts a new field
+    fn append(&mut self, fields: Fields, quoted: bool) {
+        for (i, field) in fields.into_iter().enumerate() {
+            if i != 0 && quoted {
+                self.split = true;
+                self.keep();
+            } else if i != 0 {
+                self.separate();
+            }
+            for (c, q) in field {
+                if q || !self.ifs.contains(&c) {
+                    self.push((c, q));
+                } else if c.is_whitespace() {
+                    self.separate();
+                } else if self.split && !self.delimited {
+                    // whitespaces around a non-whitespace IFS char are a part of the delimiter
+                    self.delimited = true;
+                } else {
+                    self.keep();
+                    self.split = true;
+                    self.delimited = true;
+                }
+            }
+        }
+    }
+    // "$@" without positional parameters removes the word even if it is quoted
+    fn finish(self, no_params: bool) -> Fields {
+        if no_params && self.fields.len() == 1 && self.fields[0].is_empty() {
+            return Vec::new();
+        }
+        self.fields
+            .into_iter()
+            .zip(self.kept)
+            .filter(|(field, kept)| *kept || !field.is_empty())
+            .map(|(field, _)| field)
+            .collect()
+    }
+}
+
+// removes quotes, expands parameters and commands, and splits the results into fields
+fn expand_chars(s: &[char], env: &mut Env, context: Context) -> Result<Fields, ExpansionError> {
+    let n = s.len();
+    let heredoc = context == Context::HereDoc;
+    let mut res = FieldBuilder::new(env, context != Context::Single);
+    let mut in_dquote = context == Context::DoubleQuote;
+    let mut no_params = false;
+    // in "name=value", tildes after '=' and ':' are also expanded
+    let eq_pos = s
+        .iter()
+        .position(|&c| c == '=')
+        .filter(|&x| !heredoc && x != 0 && is_name(&s[..x].iter().collect::<String>()));
+    let mut i = 0;
+    while i < n {
+        let quoted = in_dquote || heredoc;
+        let tilde_pos = i == 0 && !quoted
+            || eq_pos.is_some_and(|x| i > x && !in_dquote && (i == x + 1 || s[i - 1] == ':'));
+        match s[i] {
+            '~' if tilde_pos => {
+                let len = s[i..]
+                    .iter()
+                    .take_while(|&&c| c != '/' && (eq_pos.is_none() || c != ':'))
+                    .count();
+                let name = s[i + 1..i + len].iter().collect::<String>();
+                // a quoted tilde-prefix is not expanded
+                match expand_tilde(&name, env)
+                    .filter(|_| !name.contains(['\\', '\'', '"', '$', '`']))
+                {
+                    Some(dir) => {
+                        res.extend(quote_all(&dir, true));
+                        i += len;
+                    }
+                    None => {
+                        res.push(('~', false));
+                        i += 1;
+                    }
+                }
+            }
+            '\\' if i + 1 < n => {
+                let escaped = if quoted {
+                    ['$', '`', '\\', '\n'].contains(&s[i + 1]) || (in_dquote && s[i + 1] == '"')
+                } else {
+                    true
+                };
+                if !escaped {
+                    res.push(('\\', true));
+                }
+                if s[i + 1] != '\n' {
+                    res.push((s[i + 1], true));
+                }
+                i += 2;
+            }
+            '\'' if !quoted => {
+                res.keep();
+                i += 1;
+                while i < n && s[i] != '\'' {
+                    res.push((s[i], true));
+                    i += 1;
+                }
+                i += 1;
+            }
+            '"' if !heredoc => {
+                res.keep();
+                in_dquote = !in_dquote;
+                i += 1;
+            }
+            '<' | '>' if !quoted && s.get(i + 1) == Some(&'(') => match skip_command(s, i) {
+                Ok(end) => {
+                    let cmd = s[i + 2..end - 1].iter().collect::<String>();
+                    let path = process_substitution(&cmd, s[i] == '>', env)?;
+                    res.extend(quote_all(&path, true));
+                    i = end;
+                }
+                Err(_) => {
+                    res.push((s[i], false));
+                    i += 1;
+                }
+            },
+            '`' => match skip_backquote(s, i) {
+                Ok(end) => {
+                    let cmd = unescape_backquote(&s[i + 1..end - 1]);
+                    let output = command_substitution(&cmd, env)?;
+                    res.append(vec![quote_all(&output, quoted)], quoted);
+                    i = end;
+                }
+                Err(_) => {
+                    res.push(('`', quoted));
+                    i += 1;
+                }
+            },
+            '$' => {
+                let (fields, len) = expand_dollar(&s[i..], env, quoted)?;
+                no_params |= fields.is_empty();
+                res.append(fields, quoted);
+                i += len;
+            }
+            ch => {
+                res.push((ch, quoted));
+                i += 1;
+            }
+        }
+    }
+    Ok(res.finish(no_params))
+}
+
+pub fn expand_word(word: &str, env: &mut Env) -> Result<Fields, ExpansionError> {
+    expand_chars(&word.chars().collect::<Vec<_>>(), env, Context::Word)
+}
+
+// expands a word without field splitting. the chars of the result keep whether they are quoted
+pub fn expand_single(word: &str, env: &mut Env) -> Result<Vec<ExpChar>, ExpansionError> {
+    Ok(join_fields(expand_chars(
+        &word.chars().collect::<Vec<_>>(),
+        env,
+        Context::Single,
+    )?))
+}
+
+pub fn expand_arith_expr(expr: &str, env: &mut Env) -> Result<i64, ExpansionError> {
+    expand_arith(&expr.chars().collect::<Vec<_>>(), env)
+}
+
+pub fn expand_heredoc(body: &str, env: &mut Env) -> Result<String, ExpansionError> {
+    Ok(to_string(&join_fields(expand_chars(
+        &body.chars().collect::<Vec<_>>(),
+        env,
+        Context::HereDoc,
+    )?)))
+}
+
+// returns the end of the quote or the expansion at `i`, whose braces are not expanded
+fn skip_quoted(s: &[char], i: usize) -> Option<usize> {
+    let next = s.get(i + 1).copied();
+    match s[i] {
+        '\\' => Some((i + 2).min(s.len())),
+        '\'' => skip_single_quote(s, i).ok(),
+        '"' => skip_double_quote(s, i).ok(),
+        '`' => skip_backquote(s, i).ok(),
+        '$' if next == Some('{') => skip_param(s, i).ok(),
+        '$' | '<' | '>' if next == Some('(') => skip_command(s, i).ok(),
+        _ => None,
+    }
+}
+
+// returns the closing brace of the brace at `st` and the positions of commas in it
+fn find_brace(s: &[char], st: usize) -> Option<(usize, Vec<usize>)> {
+    let mut depth = 0;
+    let mut commas = Vec::new();
+    let mut i = st + 1;
+    while i < s.len() {
+        if let Some(end) = skip_quoted(s, i) {
+            i = end;
+            continue;
+        }
+        match s[i] {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some((i, commas)),
+            '}' => depth -= 1,
+            ',' if depth == 0 => commas.push(i),
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+// the most items of a sequence. larger ones are left as they are
+const MAX_SEQUENCE: u64 = 1 << 20;
+
+// "x..y" or "x..y..step" of numbers or letters. numbers with leading zeros are padded
+fn sequence(s: &str) -> Option<Vec<String>> {
+    let parts = s.split("..").collect::<Vec<_>>();
+    if parts.len() != 2 && parts.len() != 3 {
+        return None;
+    }
+    let step = match parts.get(2) {
+        Some(step) => step.parse::<i64>().ok()?.unsigned_abs().max(1),
+        None => 1,
+    };
+    if let (Ok(x), Ok(y)) = (parts[0].parse::<i64>(), parts[1].parse::<i64>()) {
+        let padded = parts[..2].iter().any(|x| {
+            let digits = x.trim_start_matches(['-', '+']);
+            digits.len() > 1 && digits.starts_with('0')
+        });
+        if x.abs_diff(y) / step >= MAX_SEQUENCE {
+            return None;
+        }
+        let step = step as usize;
+        let width = if padded {
+            parts[0].len().max(parts[1].len())
+        } else {
+            0
+        };
+        let values: Vec<i64> = if x <= y {
+            (x..=y).step_by(step).collect()
+        } else {
+            (y..=x).rev().step_by(step).collect()
+        };
+        return Some(
+            values
+                .into_iter()
+                .map(|v| format!("{:0width$}", v, width = width))
+                .collect(),
+        );
+    }
+    let mut chars = parts[..2].iter().map(|x| x.chars().collect::<Vec<_>>());
+    match (chars.next()?.as_slice(), chars.next()?.as_slice()) {
+        // chars are letters as in bash
+        (&[x], &[y]) if x.is_ascii_alphabetic() && y.is_ascii_alphabetic() => {
+            let (x, y, step) = (x as u32, y as u32, step as usize);
+            let values: Vec<u32> = if x <= y {
+                (x..=y).step_by(step).collect()
+            } else {
+                (y..=x).rev().step_by(step).collect()
+            };
+            Some(
+                values
+                    .into_iter()
+                    .filter_map(char::from_u32)
+                    .map(String::from)
+                    .collect(),
+            )
+        }
+        _ => None,
+    }
+}
+
+// expands "{a,b}" and "{x..y}" in a word before other expansions. braces which are quoted or
+// have neither commas nor a valid sequence are left as they are
+pub fn expand_braces(word: &str) -> Vec<String> {
+    let s = word.chars().collect::<Vec<_>>();
+    let mut i = 0;
+    while i < s.len() {
+        if let Some(end) = skip_quoted(&s, i) {
+            i = end;
+            continue;
+        }
+        if s[i] == '{' {
+            if let Some((close, commas)) = find_brace(&s, i) {
+                let items = if commas.is_empty() {
+                    sequence(&s[i + 1..close].iter().collect::<String>())
+                } else {
+                    let mut bounds = vec![i];
+                    bounds.extend(commas);
+                    bounds.push(close);
+                    Some(
+                        bounds
+                            .windows(2)
+                            .map(|x| s[x[0] + 1..x[1]].iter().collect())
+                            .collect(),
+                    )
+                };
+                if let Some(items) = items {
+                    let prefix = s[..i].iter().collect::<String>();
+                    let suffix = s[close + 1..].iter().collect::<String>();
+                    return items
+                        .iter()
+                        .flat_map(|item| expand_braces(&format!("{}{}{}", prefix, item, suffix)))
+                        .collect();
+                }
+            }
+        }
+        i += 1;
+    }
+    vec![word.to_string()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(word: &str, env: &mut Env) -> Vec<String> {
+        expand_word(word, env)
+            .unwrap()
+            .iter()
+            .map(|x| to_string(x))
+            .collect()
+    }
+
+    #[test]
+    fn parameter_defaults() {
+        let mut env = Env::for_test();
+        env.set_var("pe_empty", "");
+        env.set_var("pe_set", "v");
+        assert_eq!(fields("${pe_set:-d}", &mut env), ["v"]);
+        assert_eq!(fields("${pe_empty:-d}", &mut env), ["d"]);
+        assert_eq!(fields("${pe_unset:-d}", &mut env), ["d"]);
+        // without ':', only unset parameters are replaced
+        assert_eq!(fields("x${pe_empty-d}", &mut env), ["x"]);
+        assert_eq!(fields("${pe_unset-d}", &mut env), ["d"]);
+        assert_eq!(fields("${pe_set:+a}", &mut env), ["a"]);
+        assert_eq!(fields("x${pe_empty:+a}", &mut env), ["x"]);
+        assert_eq!(fields("${pe_empty+a}", &mut env), ["a"]);
+    }
+
+    #[test]
+    fn parameter_assignment() {
+        let mut env = Env::for_test();
+        assert_eq!(fields("${pe_assigned:=a b}", &mut env), ["a", "b"]);
+        assert_eq!(env.get_var("pe_assigned").as_deref(), Some("a b"));
+        assert_eq!(fields("${pe_assigned:=c}", &mut env), ["a", "b"]);
+        assert!(matches!(
+            expand_word("${1:=a}", &mut env),
+            Err(ExpansionError::CannotAssign(_))
+        ));
+    }
+
+    #[test]
+    fn parameter_errors() {
+        let mut env = Env::for_test();
+        assert!(matches!(
+            expand_word("${pe_unset:?}", &mut env),
+            Err(ExpansionError::NullOrUnset(name, message))
+                if name == "pe_unset" && message == "parameter null or not set"
+        ));
+        assert!(matches!(
+            expand_word("${pe_unset?oops $pe_unset}", &mut env),
+            Err(ExpansionError::NullOrUnset(_, message)) if message == "oops "
+        ));
+        assert!(matches!(
+            expand_word("${pe_unset%x}", &mut env),
+            Err(ExpansionError::BadSubstitution(_))
+        ));
+        assert!(matches!(
+            expand_word("${}", &mut env),
+            Err(ExpansionError::BadSubstitution(_))
+        ));
+    }
+
+    #[test]
+    fn parameters_are_not_expanded_in_single_quotes() {
+        let mut env = Env::for_test();
+        env.set_var("pe_quoted", "v");
+        assert_eq!(fields("'$pe_quoted'", &mut env), ["$pe_quoted"]);
+        assert_eq!(
+            fields("\"$pe_quoted\"'${pe_quoted}'", &mut env),
+            ["v${pe_quoted}"]
+        );
+        assert_eq!(fields("\\$pe_quoted", &mut env), ["$pe_quoted"]);
+        assert_eq!(fields("\"${pe_unset:-a b}\"", &mut env), ["a b"]);
+    }
+
+    #[test]
+    fn variables_are_split_into_fields() {
+        let mut env = Env::for_test();
+        env.set_var("pe_words", " a  b ");
+        assert_eq!(fields("$pe_words", &mut env), ["a", "b"]);
+        assert_eq!(fields("x${pe_words}y", &mut env), ["x", "a", "b", "y"]);
+        assert_eq!(fields("\"$pe_words\"", &mut env), [" a  b "]);
+        assert!(fields("$pe_unset", &mut env).is_empty());
+        assert_eq!(fields("\"$pe_unset\"", &mut env), [""]);
+    }
+
+    #[test]
+    fn special_parameters() {
+        let mut env = Env::for_test();
+        env.params = vec!["a b".to_string(), "c".to_string()];
+        env.shell_name = "myshell".to_string();
+        env.last_status = 3;
+        env.shell_pid = 42;
+        assert_eq!(fields("$?", &mut env), ["3"]);
+        assert_eq!(fields("$$", &mut env), ["42"]);
+        assert_eq!(fields("$0", &mut env), ["myshell"]);
+        assert_eq!(fields("$#", &mut env), ["2"]);
+        assert!(fields("$!", &mut env).is_empty());
+        env.last_bg_pid = Some(7);
+        assert_eq!(fields("$!", &mut env), ["7"]);
+    }
+
+    #[test]
+    fn positional_parameters() {
+        let mut env = Env::for_test();
+        env.params = (1..=10).map(|i| format!("p{}", i)).collect();
+        assert_eq!(fields("$1${2}", &mut env), ["p1p2"]);
+        // only one digit is taken without braces
+        assert_eq!(fields("$10", &mut env), ["p10"]);
+        assert_eq!(fields("${10}", &mut env), ["p10"]);
+        assert_eq!(fields("$1-0", &mut env), ["p1-0"]);
+        assert!(fields("${11}", &mut env).is_empty());
+    }
+
+    #[test]
+    fn quoted_at_keeps_each_parameter() {
+        let mut env = Env::for_test();
+        env.params = vec!["a b".to_string(), "".to_string(), "c".to_string()];
+        assert_eq!(fields("\"$@\"", &mut env), ["a b", "", "c"]);
+        assert_eq!(fields("\"x$@y\"", &mut env), ["xa b", "", "cy"]);
+        assert_eq!(fields("\"$*\"", &mut env), ["a b  c"]);
+        assert_eq!(fields("$@", &mut env), ["a", "b", "c"]);
+        assert_eq!(fields("$*", &mut env), ["a", "b", "c"]);
+        env.params.clear();
+        assert!(fields("\"$@\"", &mut env).is_empty());
+        assert_eq!(fields("\"$*\"", &mut env), [""]);
+    }
+
+    #[test]
+    fn backquotes_escape_only_some_chars() {
+        let unescape = |s: &str| unescape_backquote(&s.chars().collect::<Vec<_>>());
+        assert_eq!(unescape(r"a \$x \` \\ \n"), r"a $x ` \ \n");
+    }
+
+    #[test]
+    fn command_substitution_trims_newlines() {
+        let mut env = Env::for_test();
+        assert_eq!(
+            fields("x$(/bin/echo -e 'a b\\n\\n')", &mut env),
+            ["xa", "b"]
+        );
+        assert_eq!(fields("\"`/bin/echo 'a  b'`\"", &mut env), ["a  b"]);
+        assert_eq!(env.last_status, 0);
+        assert!(fields("$(/bin/false)", &mut env).is_empty());
+        assert_eq!(env.last_status, 1);
+        assert!(matches!(
+            expand_word("$(if)", &mut env),
+            Err(ExpansionError::CommandSubstitution(_))
+        ));
+    }
+
+    #[test]
+    fn brace_lists() {
+        assert_eq!(expand_braces("a{b,c}d"), ["abd", "acd"]);
+        assert_eq!(expand_braces("{a,}"), ["a", ""]);
+        assert_eq!(expand_braces("x{a,{b,c}1}"), ["xa", "xb1", "xc1"]);
+        assert_eq!(expand_braces("{a,b}{1,2}"), ["a1", "a2", "b1", "b2"]);
+        // braces without commas or a sequence are kept
+        assert_eq!(expand_braces("{a}"), ["{a}"]);
+        assert_eq!(expand_braces("{}"), ["{}"]);
+        assert_eq!(expand_braces("{a,b"), ["{a,b"]);
+        assert_eq!(expand_braces("${x}{1,2}"), ["${x}1", "${x}2"]);
+    }
+
+    #[test]
+    fn quoted_braces_are_kept() {
+        assert_eq!(expand_braces("'{a,b}'"), ["'{a,b}'"]);
+        assert_eq!(expand_braces("\"{a,b}\""), ["\"{a,b}\""]);
+        assert_eq!(expand_braces(r"\{a,b}"), [r"\{a,b}"]);
+        assert_eq!(expand_braces(r"{a\,b}"), [r"{a\,b}"]);
+        assert_eq!(expand_braces("{'a,b',c}"), ["'a,b'", "c"]);
+        assert_eq!(expand_braces("{$(a,b),c}"), ["$(a,b)", "c"]);
+    }
+
+    #[test]
+    fn brace_sequences() {
+        assert_eq!(expand_braces("{1..4}"), ["1", "2", "3", "4"]);
+        assert_eq!(expand_braces("{1..10..3}"), ["1", "4", "7", "10"]);
+        assert_eq!(expand_braces("{3..-1..2}"), ["3", "1", "-1"]);
+        assert_eq!(expand_braces("{z..w}"), ["z", "y", "x", "w"]);
+        assert_eq!(expand_braces("{a..e..-2}"), ["a", "c", "e"]);
+        assert_eq!(expand_braces("{08..11}"), ["08", "09", "10", "11"]);
+        assert_eq!(expand_braces("{-1..01}"), ["-1", "00", "01"]);
+        assert_eq!(expand_braces("x{1..2}{a..b}"), ["x1a", "x1b", "x2a", "x2b"]);
+        assert_eq!(expand_braces("{1..b}"), ["{1..b}"]);
+        assert_eq!(expand_braces("{1..2..x}"), ["{1..2..x}"]);
+        assert_eq!(expand_braces("{ab..c}"), ["{ab..c}"]);
+        // too long sequences are not expanded
+        assert_eq!(
+            expand_braces("{1..9223372036854775807}"),
+            ["{1..9223372036854775807}"]
+        );
+        assert_eq!(
+            expand_braces("{-9223372036854775808..9223372036854775807..2}"),
+            ["{-9223372036854775808..9223372036854775807..2}"]
+        );
+        assert_eq!(
+            expand_braces("{0..9223372036854775807..4611686018427387904}"),
+            ["0", "4611686018427387904"]
+        );
+    }
+
+    #[test]
+    fn tilde_prefixes() {
+        let mut env = Env::for_test();
+        env.set_var("HOME", "/h");
+        env.set_var("PWD", "/p");
+        env.set_var("OLDPWD", "/o");
+        assert_eq!(fields("~", &mut env), ["/h"]);
+        assert_eq!(fields("~/a", &mut env), ["/h/a"]);
+        assert_eq!(fields("~+/a", &mut env), ["/p/a"]);
+        assert_eq!(fields("~-", &mut env), ["/o"]);
+        assert_eq!(fields("~root", &mut env), ["/root"]);
+        assert_eq!(fields("~no_such_user_x/a", &mut env), ["~no_such_user_x/a"]);
+        // only at the head of a word, or after '=' and ':' of an assignment
+        assert_eq!(fields("a~", &mut env), ["a~"]);
+        assert_eq!(fields("x=~/a:~-:b~", &mut env), ["x=/h/a:/o:b~"]);
+        assert_eq!(fields("-x=~", &mut env), ["-x=~"]);
+        assert_eq!(fields("a:~", &mut env), ["a:~"]);
+        // quoted tildes are not expanded, and the result is not split
+        assert_eq!(fields("'~'", &mut env), ["~"]);
+        assert_eq!(fields("\\~", &mut env), ["~"]);
+        assert_eq!(fields("\"~\"", &mut env), ["~"]);
+        assert_eq!(fields("~\"\"", &mut env), ["~"]);
+        env.set_var("HOME", "/a b");
+        assert_eq!(fields("~", &mut env), ["/a b"]);
+    }
+
+    #[test]
+    fn field_splitting_by_ifs() {
+        // IFS (None if unset), the value of $v, the word, and the fields
+        let cases: [(Option<&str>, &str, &str, &[&str]); 15] = [
+            (None, " a \t b\n", "$v", &["a", "b"]),
+            (None, "a:b", "$v", &["a:b"]),
+            (Some(":"), "a::b:", "$v", &["a", "", "b"]),
+            (Some(":"), ":a", "$v", &["", "a"]),
+            (Some(":"), "a:", "${v}b", &["a", "b"]),
+            (Some(":"), "a b", "$v", &["a b"]),
+            (Some(": "), " a :: b ", "$v", &["a", "", "b"]),
+            (Some(": "), "a : b", "$v", &["a", "b"]),
+            (Some(": "), "  ", "x${v}y", &["x", "y"]),
+            (Some(" "), "a\tb", "$v", &["a\tb"]),
+            (Some(""), " a b ", "$v", &[" a b "]),
+            (Some(""), "", "$v", &[]),
+            (Some(":"), "a:b", "\"$v\"", &["a:b"]),
+            (Some(":"), "a:b", "x=$v", &["x=a", "b"]),
+            (Some(":"), "", "\"\"$v", &[""]),
+        ];
+        for (ifs, value, word, expected) in cases {
+            let mut env = Env::for_test();
+            if let Some(ifs) = ifs {
+                env.set_var("IFS", ifs);
+            }
+            env.set_var("v", value);
+            assert_eq!(fields(word, &mut env), expected, "{:?} {:?}", ifs, value);
+        }
+    }
+
+    #[test]
+    fn positional_parameters_and_ifs() {
+        // IFS, and the fields of "$*", $*, "$@" and $@
+        let cases: [(Option<&str>, [&[&str]; 4]); 3] = [
+            (
+                None,
+                [
+                    &["a b c"],
+                    &["a", "b", "c"],
+                    &["a b", "c"],
+                    &["a", "b", "c"],
+                ],
+            ),
+            (
+                Some(":"),
+                [&["a b:c"], &["a b", "c"], &["a b", "c"], &["a b", "c"]],
+            ),
+            (
+                Some(""),
+                [&["a bc"], &["a b", "c"], &["a b", "c"], &["a b", "c"]],
+            ),
+        ];
+        for (ifs, expected) in cases {
+            let mut env = Env::for_test();
+            env.params = vec!["a b".to_string(), "c".to_string()];
+            if let Some(ifs) = ifs {
+                env.set_var("IFS", ifs);
+            }
+            for (word, expected) in ["\"$*\"", "$*", "\"$@\"", "$@"].iter().zip(expected) {
+                assert_eq!(fields(word, &mut env), expected, "{:?} {}", ifs, word);
+            }
+        }
+    }
+}
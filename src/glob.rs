@@ -0,0 +1,154 @@
+use crate::expand::ExpChar;
+use crate::pattern::{has_glob, matches};
+use std::fs::read_dir;
+use std::path::Path;
+
+// names in `dir`. hidden files are listed only if `hidden` is true, as in `complete::comp`
+fn list_dir(dir: &str, hidden: bool) -> Vec<String> {
+    let dir = if dir.is_empty() { "." } else { dir };
+    match read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|x| x.ok()?.file_name().to_str().map(|x| x.to_string()))
+            .filter(|x| hidden || !x.starts_with('.'))
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn is_dir(path: &str) -> bool {
+    Path::new(if path.is_empty() { "." } else { path }).is_dir()
+}
+
+// subdirectories of `dir` (including itself) searched by "**". symbolic links are not followed
+fn walk_dirs(dir: &str, res: &mut Vec<String>) {
+    res.push(dir.to_string());
+    for name in list_dir(dir, false) {
+        let path = format!("{}{}", dir, name);
+        if std::fs::symlink_metadata(&path).is_ok_and(|x| x.is_dir()) {
+            walk_dirs(&(path + "/"), res);
+        }
+    }
+}
+
+// expands `components` under `prefix`, which is empty or ends with '/'
+fn glob_rec(prefix: &str, components: &[Vec<ExpChar>], res: &mut Vec<String>) {
+    let Some((component, rest)) = components.split_first() else {
+        res.push(prefix.to_string());
+        return;
+    };
+    if rest.is_empty() && component.is_empty() {
+        // a trailing '/' matches only directories
+        if is_dir(prefix) {
+            res.push(prefix.to_string());
+        }
+        return;
+    }
+    if !has_glob(component) {
+        let path = format!(
+            "{}{}",
+            prefix,
+            component.iter().map(|&(c, _)| c).collect::<String>()
+        );
+        if rest.is_empty() {
+            if std::fs::symlink_metadata(&path).is_ok() {
+                res.push(path);
+            }
+        } else {
+            glob_rec(&(path + "/"), rest, res);
+        }
+        return;
+    }
+    if component == &[('*', false), ('*', false)] {
+        // "**" matches zero or more directories
+        let mut dirs = Vec::new();
+        walk_dirs(prefix, &mut dirs);
+        for dir in dirs {
+            if rest.is_empty() {
+                for name in list_dir(&dir, false) {
+                    res.push(format!("{}{}", dir, name));
+                }
+            } else {
+                glob_rec(&dir, rest, res);
+            }
+        }
+        return;
+    }
+    let hidden = component.first().is_some_and(|&(c, _)| c == '.');
+    for name in list_dir(prefix, hidden) {
+        if matches(component, &name.chars().collect::<Vec<_>>()) {
+            let path = format!("{}{}", prefix, name);
+            if rest.is_empty() {
+                res.push(path);
+            } else if is_dir(&path) {
+                glob_rec(&(path + "/"), rest, res);
+            }
+        }
+    }
+}
+
+// returns the sorted paths matching the pattern
+pub fn glob(pattern: &[ExpChar]) -> Vec<String> {
+    let components = pattern
+        .split(|&(c, _)| c == '/')
+        .map(|x| x.to_vec())
+        .collect::<Vec<_>>();
+    let mut res = Vec::new();
+    match components.split_first() {
+        // an absolute path
+        Some((head, rest)) if head.is_empty() => glob_rec("/", rest, &mut res),
+        _ => glob_rec("", &components, &mut res),
+    }
+    res.sort();
+    res.dedup();
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    fn unquoted(s: &str) -> Vec<ExpChar> {
+        s.chars().map(|c| (c, false)).collect()
+    }
+
+    #[test]
+    fn glob_in_a_directory() {
+        let dir = std::env::temp_dir().join(format!("myshell_glob_{}", std::process::id()));
+        let root = format!("{}/", dir.to_str().unwrap());
+        for path in [
+            "a.txt",
+            "b.txt",
+            "c.rs",
+            ".hidden.txt",
+            "*",
+            "sub/d.txt",
+            "sub/x/e.txt",
+        ] {
+            let path = dir.join(path);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            write(path, "").unwrap();
+        }
+        let names = |pattern: &str| -> Vec<String> {
+            glob(&unquoted(&(root.clone() + pattern)))
+                .into_iter()
+                .map(|x| x[root.len()..].to_string())
+                .collect()
+        };
+        assert_eq!(names("*.txt"), ["a.txt", "b.txt"]);
+        assert_eq!(names("?.*"), ["a.txt", "b.txt", "c.rs"]);
+        assert_eq!(names(".*.txt"), [".hidden.txt"]);
+        assert_eq!(names("[ac]*"), ["a.txt", "c.rs"]);
+        assert_eq!(names("*/"), ["sub/"]);
+        assert_eq!(names("s*/*.txt"), ["sub/d.txt"]);
+        assert_eq!(
+            names("**/*.txt"),
+            ["a.txt", "b.txt", "sub/d.txt", "sub/x/e.txt"]
+        );
+        assert!(names("*.md").is_empty());
+        // quoted metacharacters are literal
+        let pattern = [unquoted(&root), vec![('*', true)]].concat();
+        assert_eq!(glob(&pattern), [root.clone() + "*"]);
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,7 +1,8 @@
+use crate::utils::remove_quotes;
 use std::fmt::{Display, Formatter};
 use thiserror::Error;
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub enum Operator {
     And,
     AndAnd,
@@ -9,10 +10,22 @@ pub enum Operator {
     Pipe,
     Less,
     LessLess,
-    ErrorRedirect,
+    LessLessDash,
+    LessLessLess,
+    LessAnd,
+    LessGreater,
     Greater,
+    GreaterPipe,
     GreaterGreater,
+    GreaterAnd,
+    AndGreater,
     SemiColon,
+    DoubleSemiColon,
+    SemiColonAnd,
+    DoubleSemiColonAnd,
+    NewLine,
+    LeftParen,
+    RightParen,
 }
 
 impl Operator {
@@ -24,14 +37,45 @@ impl Operator {
             Operator::Pipe => "|",
             Operator::Less => "<",
             Operator::LessLess => "<<",
+            Operator::LessLessDash => "<<-",
+            Operator::LessLessLess => "<<<",
+            Operator::LessAnd => "<&",
+            Operator::LessGreater => "<>",
             Operator::Greater => ">",
+            Operator::GreaterPipe => ">|",
             Operator::GreaterGreater => ">>",
-            Operator::ErrorRedirect => "2>",
+            Operator::GreaterAnd => ">&",
+            Operator::AndGreater => "&>",
             Operator::SemiColon => ";",
+            Operator::DoubleSemiColon => ";;",
+            Operator::SemiColonAnd => ";&",
+            Operator::DoubleSemiColonAnd => ";;&",
+            Operator::NewLine => "\\n",
+            Operator::LeftParen => "(",
+            Operator::RightParen => ")",
         }
     }
 }
 
+impl Operator {
+    pub fn is_redirect(&self) -> bool {
+        matches!(
+            self,
+            Operator::Less
+                | Operator::LessLess
+                | Operator::LessLessDash
+                | Operator::LessLessLess
+                | Operator::LessAnd
+                | Operator::LessGreater
+                | Operator::Greater
+                | Operator::GreaterPipe
+                | Operator::GreaterGreater
+                | Operator::GreaterAnd
+                | Operator::AndGreater
+        )
+    }
+}
+
 impl Display for Operator {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.to_str())
@@ -45,80 +89,652 @@ impl std::fmt::Debug for Operator {
 }
 
 #[derive(Clone, Error, Debug)]
-pub enum LexError {}
+pub enum LexError {
+    #[error("unterminated quote {0} (at char {1})")]
+    UnterminatedQuote(char, usize),
+    #[error("backslash at the end of input (at char {0})")]
+    TrailingBackslash(usize),
+    #[error("here-document is not terminated by \"{0}\" (at char {1})")]
+    UnterminatedHereDoc(String, usize),
+    #[error("unterminated expansion, missing {0} (at char {1})")]
+    UnterminatedExpansion(char, usize),
+}
+
+impl LexError {
+    // the input may become valid if more lines are given
+    pub fn is_incomplete(&self) -> bool {
+        match self {
+            LexError::UnterminatedQuote(..) => true,
+            LexError::TrailingBackslash(_) => true,
+            LexError::UnterminatedHereDoc(..) => true,
+            LexError::UnterminatedExpansion(..) => true,
+        }
+    }
+    pub fn span(&self) -> Span {
+        match self {
+            LexError::UnterminatedQuote(_, i) => Span::new(*i, *i + 1),
+            LexError::TrailingBackslash(i) => Span::new(*i, *i + 1),
+            LexError::UnterminatedHereDoc(delim, i) => Span::new(*i, *i + delim.chars().count()),
+            LexError::UnterminatedExpansion(_, i) => Span::new(*i, *i + 2),
+        }
+    }
+    pub fn expected(&self) -> String {
+        match self {
+            LexError::UnterminatedQuote(c, _) => format!("expected a closing {}", c),
+            LexError::TrailingBackslash(_) => "expected a character to escape".to_string(),
+            LexError::UnterminatedHereDoc(delim, _) => format!("expected a line \"{}\"", delim),
+            LexError::UnterminatedExpansion(c, _) => format!("expected a closing {}", c),
+        }
+    }
+}
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HereDoc {
+    pub body: String,
+    // false if the delimiter is quoted
+    pub expand: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Token {
     Operator(Operator),
     String(String),
+    // the file descriptor written just before a redirection (e.g. "2" of "2>")
+    IoNumber(i32),
+    // replaces the delimiter word after "<<"
+    HereDoc(HereDoc),
+}
+
+// position of a token in the input, counted in chars
+#[derive(Clone, Copy, Debug)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+}
+
+const SPECIAL_CHARS: [char; 7] = ['&', '|', '<', '>', ';', '(', ')'];
+
+fn is_spl(x: char) -> bool {
+    SPECIAL_CHARS.contains(&x) || x.is_whitespace()
+}
+
+// "<(" and ">(" of a process substitution are a part of a word
+fn is_process_subst(s: &[char], i: usize) -> bool {
+    (s[i] == '<' || s[i] == '>') && s.get(i + 1) == Some(&'(')
+}
+
+// returns the end of the word starting at `i`. quotes and backslashes are kept in the word
+// and removed later by `expand::expand_word`
+fn skip_word(s: &[char], mut i: usize) -> Result<usize, LexError> {
+    let n = s.len();
+    while i < n && (!is_spl(s[i]) || is_process_subst(s, i)) {
+        match s[i] {
+            '\\' if i + 1 == n => return Err(LexError::TrailingBackslash(i)),
+            '<' | '>' => i = skip_command(s, i)?,
+            '\\' => {
+                i += 2;
+            }
+            '\'' => i = skip_single_quote(s, i)?,
+            '"' => i = skip_double_quote(s, i)?,
+            '`' => i = skip_backquote(s, i)?,
+            '$' if i + 1 < n && s[i + 1] == '{' => i = skip_param(s, i)?,
+            '$' if i + 1 < n && s[i + 1] == '(' => i = skip_command(s, i)?,
+            _ => {
+                i += 1;
+            }
+        }
+    }
+    Ok(i)
+}
+
+pub fn skip_single_quote(s: &[char], i: usize) -> Result<usize, LexError> {
+    match s[i + 1..].iter().position(|&c| c == '\'') {
+        Some(len) => Ok(i + len + 2),
+        None => Err(LexError::UnterminatedQuote('\'', i)),
+    }
+}
+
+pub fn skip_double_quote(s: &[char], st: usize) -> Result<usize, LexError> {
+    let n = s.len();
+    let mut i = st + 1;
+    loop {
+        if i >= n {
+            return Err(LexError::UnterminatedQuote('"', st));
+        }
+        match s[i] {
+            '\\' => i += 2,
+            '"' => return Ok(i + 1),
+            '`' => i = skip_backquote(s, i)?,
+            '$' if i + 1 < n && s[i + 1] == '{' => i = skip_param(s, i)?,
+            '$' if i + 1 < n && s[i + 1] == '(' => i = skip_command(s, i)?,
+            _ => i += 1,
+        }
+    }
+}
+
+// returns the end of "${...}" starting at `st`. braces in the word of "${x:-word}" are nested
+pub fn skip_param(s: &[char], st: usize) -> Result<usize, LexError> {
+    let n = s.len();
+    let mut i = st + 2;
+    let mut depth = 0;
+    while i < n {
+        match s[i] {
+            '\\' => i += 2,
+            '\'' => i = skip_single_quote(s, i)?,
+            '"' => i = skip_double_quote(s, i)?,
+            '`' => i = skip_backquote(s, i)?,
+            '$' if i + 1 < n && s[i + 1] == '{' => i = skip_param(s, i)?,
+            '$' if i + 1 < n && s[i + 1] == '(' => i = skip_command(s, i)?,
+            '{' => {
+                depth += 1;
+                i += 1;
+            }
+            '}' if depth == 0 => return Ok(i + 1),
+            '}' => {
+                depth -= 1;
+                i += 1;
+            }
+            _ => i += 1,
+        }
+    }
+    Err(LexError::UnterminatedExpansion('}', st))
 }
 
-pub fn lex(s: &str) -> Result<Vec<Token>, LexError> {
-    let mut s = s.chars().collect::<Vec<_>>();
-    s.push(' ');
+// returns the end of "$(...)", "<(...)" or ">(...)" starting at `st`. parentheses in the
+// command are nested
+pub fn skip_command(s: &[char], st: usize) -> Result<usize, LexError> {
+    let n = s.len();
+    let mut i = st + 2;
+    let mut depth = 0;
+    while i < n {
+        match s[i] {
+            '\\' => i += 2,
+            '\'' => i = skip_single_quote(s, i)?,
+            '"' => i = skip_double_quote(s, i)?,
+            '`' => i = skip_backquote(s, i)?,
+            '$' if i + 1 < n && s[i + 1] == '{' => i = skip_param(s, i)?,
+            '$' if i + 1 < n && s[i + 1] == '(' => i = skip_command(s, i)?,
+            '(' => {
+                depth += 1;
+                i += 1;
+            }
+            ')' if depth == 0 => return Ok(i + 1),
+            ')' => {
+                depth -= 1;
+                i += 1;
+            }
+            _ => i += 1,
+        }
+    }
+    Err(LexError::UnterminatedExpansion(')', st))
+}
+
+// returns the end of "`...`" starting at `st`
+pub fn skip_backquote(s: &[char], st: usize) -> Result<usize, LexError> {
+    let mut i = st + 1;
+    while i < s.len() {
+        match s[i] {
+            '\\' => i += 2,
+            '`' => return Ok(i + 1),
+            _ => i += 1,
+        }
+    }
+    Err(LexError::UnterminatedQuote('`', st))
+}
+
+// reads lines from `i` until the delimiter, and returns the body and whether the delimiter is quoted
+fn read_heredoc(
+    s: &[char],
+    i: &mut usize,
+    delim: &str,
+    strip_tabs: bool,
+) -> Option<(String, bool)> {
+    let quoted = delim.contains(['\'', '"', '\\']);
+    let delim = remove_quotes(delim);
+    let mut body = String::new();
+    while *i < s.len() {
+        let len = s[*i..].iter().take_while(|&&c| c != '\n').count();
+        let mut line = s[*i..*i + len].iter().collect::<String>();
+        *i = (*i + len + 1).min(s.len());
+        if strip_tabs {
+            line = line.trim_start_matches('\t').to_string();
+        }
+        if line == delim {
+            return Some((body, quoted));
+        }
+        body.push_str(&line);
+        body.push('\n');
+    }
+    None
+}
+
+fn is_arith_for(s: &[char], i: usize, tokens: &[Token]) -> bool {
+    s.get(i + 1) == Some(&'(')
+        && matches!(tokens.last(), Some(Token::String(word)) if word == "for")
+}
+
+// digits just before '<' or '>' are the file descriptor of a redirection. those before
+// "<(" or ">(" are a part of the word as in bash
+fn io_number(s: &[char], i: usize) -> Option<(i32, usize)> {
+    let len = s[i..].iter().take_while(|c| c.is_ascii_digit()).count();
+    if len != 0
+        && i + len < s.len()
+        && (s[i + len] == '<' || s[i + len] == '>')
+        && !is_process_subst(s, i + len)
+    {
+        let fd = s[i..i + len].iter().collect::<String>().parse().ok()?;
+        Some((fd, len))
+    } else {
+        None
+    }
+}
+
+pub fn lex(s: &str) -> Result<Vec<(Token, Span)>, LexError> {
+    lex_impl(s).map(|(tokens, _)| tokens)
+}
+
+// returns true if the end of `s` is in a comment
+pub fn ends_in_comment(s: &str) -> bool {
+    lex_impl(s).is_ok_and(|(_, in_comment)| in_comment)
+}
+
+fn lex_impl(s: &str) -> Result<(Vec<(Token, Span)>, bool), LexError> {
+    let s = s.chars().collect::<Vec<_>>();
     let n = s.len();
     let mut tokens = Vec::new();
+    let mut spans: Vec<Span> = Vec::new();
     let mut i = 0;
-    let mut st = 0;
-
-    const SPECIAL_CHARS: [char; 5] = ['&', '|', '<', '>', ';'];
-    let is_spl = |x: char| SPECIAL_CHARS.contains(&x) || x.is_whitespace();
+    let mut in_comment = false;
+    // here-documents whose bodies are not read yet
+    let mut heredocs: Vec<(usize, String, bool)> = Vec::new();
 
     while i < n {
-        if i + 1 < n && s[i] == '2' && s[i + 1] == '>' {
-            if st != i {
-                tokens.push(Token::String(s[st..i].iter().collect::<String>()));
+        in_comment = false;
+        let st = i;
+        if s[i] == '#' {
+            // comment until the end of line
+            in_comment = true;
+            while i < n && s[i] != '\n' {
+                i += 1;
             }
-            tokens.push(Token::Operator(Operator::ErrorRedirect));
-            i += 2;
-            st = i;
-        } else if is_spl(s[i]) {
-            if st != i {
-                tokens.push(Token::String(s[st..i].iter().collect::<String>()));
+        } else if let Some((fd, len)) = io_number(&s, i) {
+            tokens.push(Token::IoNumber(fd));
+            i += len;
+        } else if s[i] == '&' {
+            if i + 1 < n && s[i + 1] == '&' {
+                tokens.push(Token::Operator(Operator::AndAnd));
+                i += 2;
+            } else if i + 1 < n && s[i + 1] == '>' {
+                tokens.push(Token::Operator(Operator::AndGreater));
+                i += 2;
+            } else {
+                tokens.push(Token::Operator(Operator::And));
+                i += 1;
             }
-            if s[i] == '&' {
-                if i + 1 < n && s[i + 1] == '&' {
-                    tokens.push(Token::Operator(Operator::AndAnd));
-                    i += 2;
-                } else {
-                    tokens.push(Token::Operator(Operator::And));
-                    i += 1;
-                }
-            } else if s[i] == '|' {
-                if i + 1 < n && s[i + 1] == '|' {
-                    tokens.push(Token::Operator(Operator::OrOr));
-                    i += 2;
-                } else {
-                    tokens.push(Token::Operator(Operator::Pipe));
-                    i += 1;
-                }
-            } else if s[i] == '<' {
-                if i + 1 < n && s[i + 1] == '<' {
-                    tokens.push(Token::Operator(Operator::LessLess));
-                    i += 2;
-                } else {
-                    tokens.push(Token::Operator(Operator::Less));
-                    i += 1;
-                }
-            } else if s[i] == '>' {
-                if i + 1 < n && s[i + 1] == '>' {
-                    tokens.push(Token::Operator(Operator::GreaterGreater));
-                    i += 2;
-                } else {
-                    tokens.push(Token::Operator(Operator::Greater));
-                    i += 1;
-                }
-            } else if s[i] == ';' {
-                tokens.push(Token::Operator(Operator::SemiColon));
+        } else if s[i] == '|' {
+            if i + 1 < n && s[i + 1] == '|' {
+                tokens.push(Token::Operator(Operator::OrOr));
+                i += 2;
+            } else {
+                tokens.push(Token::Operator(Operator::Pipe));
                 i += 1;
+            }
+        } else if s[i] == '<' && !is_process_subst(&s, i) {
+            if i + 2 < n && s[i + 1] == '<' && s[i + 2] == '<' {
+                tokens.push(Token::Operator(Operator::LessLessLess));
+                i += 3;
+            } else if i + 2 < n && s[i + 1] == '<' && s[i + 2] == '-' {
+                tokens.push(Token::Operator(Operator::LessLessDash));
+                i += 3;
+            } else if i + 1 < n && s[i + 1] == '<' {
+                tokens.push(Token::Operator(Operator::LessLess));
+                i += 2;
+            } else if i + 1 < n && s[i + 1] == '&' {
+                tokens.push(Token::Operator(Operator::LessAnd));
+                i += 2;
+            } else if i + 1 < n && s[i + 1] == '>' {
+                tokens.push(Token::Operator(Operator::LessGreater));
+                i += 2;
             } else {
-                // whitespace
+                tokens.push(Token::Operator(Operator::Less));
                 i += 1;
             }
-            st = i;
-        } else {
+        } else if s[i] == '>' && !is_process_subst(&s, i) {
+            if i + 1 < n && s[i + 1] == '>' {
+                tokens.push(Token::Operator(Operator::GreaterGreater));
+                i += 2;
+            } else if i + 1 < n && s[i + 1] == '&' {
+                tokens.push(Token::Operator(Operator::GreaterAnd));
+                i += 2;
+            } else if i + 1 < n && s[i + 1] == '|' {
+                tokens.push(Token::Operator(Operator::GreaterPipe));
+                i += 2;
+            } else {
+                tokens.push(Token::Operator(Operator::Greater));
+                i += 1;
+            }
+        } else if s[i] == ';' {
+            // ";;", ";&" and ";;&" end items of "case"
+            if i + 2 < n && s[i + 1] == ';' && s[i + 2] == '&' {
+                tokens.push(Token::Operator(Operator::DoubleSemiColonAnd));
+                i += 3;
+            } else if i + 1 < n && s[i + 1] == ';' {
+                tokens.push(Token::Operator(Operator::DoubleSemiColon));
+                i += 2;
+            } else if i + 1 < n && s[i + 1] == '&' {
+                tokens.push(Token::Operator(Operator::SemiColonAnd));
+                i += 2;
+            } else {
+                tokens.push(Token::Operator(Operator::SemiColon));
+                i += 1;
+            }
+        } else if s[i] == '(' && is_arith_for(&s, i, &tokens) {
+            // "((...))" of an arithmetic for loop is a word, whose ';' and '<' are not operators
+            let end = skip_command(&s, i)?;
+            if s.get(end) != Some(&')') {
+                return Err(LexError::UnterminatedExpansion(')', st));
+            }
+            i = end + 1;
+            tokens.push(Token::String(s[st..i].iter().collect()));
+        } else if s[i] == '(' {
+            tokens.push(Token::Operator(Operator::LeftParen));
             i += 1;
+        } else if s[i] == ')' {
+            tokens.push(Token::Operator(Operator::RightParen));
+            i += 1;
+        } else if s[i] == '\n' {
+            tokens.push(Token::Operator(Operator::NewLine));
+            i += 1;
+            // bodies of here-documents start from the next line
+            for (idx, delim, strip_tabs) in heredocs.drain(..) {
+                let (body, quoted) =
+                    read_heredoc(&s, &mut i, &delim, strip_tabs).ok_or_else(|| {
+                        LexError::UnterminatedHereDoc(delim.clone(), spans[idx].start)
+                    })?;
+                tokens[idx] = Token::HereDoc(HereDoc {
+                    body,
+                    expand: !quoted,
+                });
+            }
+        } else if s[i].is_whitespace() {
+            i += 1;
+        } else if s[i] == '\\' && i + 1 < n && s[i + 1] == '\n' {
+            // line continuation
+            i += 2;
+        } else {
+            i = skip_word(&s, i)?;
+            let word = s[st..i].iter().collect::<String>();
+            if let Some(Token::Operator(op @ (Operator::LessLess | Operator::LessLessDash))) =
+                tokens.last()
+            {
+                let strip_tabs = matches!(op, Operator::LessLessDash);
+                heredocs.push((tokens.len(), word.clone(), strip_tabs));
+            }
+            tokens.push(Token::String(word));
+        }
+        if spans.len() != tokens.len() {
+            spans.push(Span::new(st, i));
         }
     }
-    Ok(tokens)
+    if let Some((idx, delim, _)) = heredocs.first() {
+        return Err(LexError::UnterminatedHereDoc(
+            delim.clone(),
+            spans[*idx].start,
+        ));
+    }
+    Ok((tokens.into_iter().zip(spans).collect(), in_comment))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(s: &str) -> Vec<Token> {
+        lex(s)
+            .unwrap()
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect()
+    }
+
+    fn word(s: &str) -> Token {
+        Token::String(s.to_string())
+    }
+
+    #[test]
+    fn quotes_and_escapes_are_kept_in_words() {
+        assert_eq!(
+            tokens(r#"git commit -m "fix bug" 'a|b' c\ d"#),
+            vec![
+                word("git"),
+                word("commit"),
+                word("-m"),
+                word("\"fix bug\""),
+                word("'a|b'"),
+                word("c\\ d"),
+            ]
+        );
+        assert_eq!(tokens(r#"a"b"'c'd"#), vec![word(r#"a"b"'c'd"#)]);
+        assert_eq!(tokens(r#""a\"b""#), vec![word(r#""a\"b""#)]);
+        assert_eq!(
+            tokens("a\\;b"),
+            vec![word("a\\;b")],
+            "an escaped operator is a part of the word"
+        );
+    }
+
+    #[test]
+    fn operators_split_words() {
+        assert_eq!(
+            tokens("a|b&&c;d"),
+            vec![
+                word("a"),
+                Token::Operator(Operator::Pipe),
+                word("b"),
+                Token::Operator(Operator::AndAnd),
+                word("c"),
+                Token::Operator(Operator::SemiColon),
+                word("d"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unterminated_quotes() {
+        assert!(matches!(
+            lex("echo 'abc"),
+            Err(LexError::UnterminatedQuote('\'', 5))
+        ));
+        assert!(matches!(
+            lex("echo a\"bc"),
+            Err(LexError::UnterminatedQuote('"', 6))
+        ));
+        assert!(matches!(
+            lex(r#"echo "a\""#),
+            Err(LexError::UnterminatedQuote('"', 5))
+        ));
+    }
+
+    #[test]
+    fn comments_run_to_the_end_of_line() {
+        assert_eq!(
+            tokens("make build  # release"),
+            vec![word("make"), word("build")]
+        );
+        assert_eq!(
+            tokens("# a\necho b # c\nd"),
+            vec![
+                Token::Operator(Operator::NewLine),
+                word("echo"),
+                word("b"),
+                Token::Operator(Operator::NewLine),
+                word("d"),
+            ]
+        );
+        // '#' inside a word or quotes does not start a comment
+        assert_eq!(
+            tokens("a#b '#c' \\#d"),
+            vec![word("a#b"), word("'#c'"), word("\\#d")]
+        );
+        assert!(ends_in_comment("echo a # b"));
+        assert!(!ends_in_comment("echo a # b\nc"));
+        assert!(!ends_in_comment("echo a#b"));
+    }
+
+    #[test]
+    fn spans_are_counted_in_chars() {
+        let spans = lex("ab  'c d'|é >f")
+            .unwrap()
+            .into_iter()
+            .map(|(_, span)| (span.start, span.end))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            vec![(0, 2), (4, 9), (9, 10), (10, 11), (12, 13), (13, 14)]
+        );
+    }
+
+    fn heredoc(body: &str, expand: bool) -> Token {
+        Token::HereDoc(HereDoc {
+            body: body.to_string(),
+            expand,
+        })
+    }
+
+    #[test]
+    fn heredoc_bodies_replace_delimiters() {
+        assert_eq!(
+            tokens("cat <<EOF; echo a\nhello $x\n EOF\nEOF\necho b"),
+            vec![
+                word("cat"),
+                Token::Operator(Operator::LessLess),
+                heredoc("hello $x\n EOF\n", true),
+                Token::Operator(Operator::SemiColon),
+                word("echo"),
+                word("a"),
+                Token::Operator(Operator::NewLine),
+                word("echo"),
+                word("b"),
+            ]
+        );
+        // bodies are read in order after the line
+        assert_eq!(
+            tokens("a <<X <<'Y'\n1\nX\n2\nY\n"),
+            vec![
+                word("a"),
+                Token::Operator(Operator::LessLess),
+                heredoc("1\n", true),
+                Token::Operator(Operator::LessLess),
+                heredoc("2\n", false),
+                Token::Operator(Operator::NewLine),
+            ]
+        );
+    }
+
+    #[test]
+    fn heredoc_options() {
+        // "<<-" strips leading tabs
+        assert_eq!(tokens("a <<-E\n\t\tx\n\tE\n")[2], heredoc("x\n", true));
+        // a quoted delimiter disables expansions
+        for delim in ["\"E\"", "'E'", "\\E", "E''"] {
+            let s = format!("a <<{}\n$x\nE\n", delim);
+            assert_eq!(tokens(&s)[2], heredoc("$x\n", false));
+        }
+    }
+
+    #[test]
+    fn unterminated_heredocs() {
+        assert!(matches!(
+            lex("cat <<EOF\nabc\n"),
+            Err(LexError::UnterminatedHereDoc(delim, 6)) if delim == "EOF"
+        ));
+        assert!(matches!(
+            lex("cat <<EOF"),
+            Err(LexError::UnterminatedHereDoc(..))
+        ));
+    }
+
+    #[test]
+    fn here_strings_are_not_heredocs() {
+        assert_eq!(
+            tokens("base64 -d <<< \"$TOKEN\"<<<x"),
+            vec![
+                word("base64"),
+                word("-d"),
+                Token::Operator(Operator::LessLessLess),
+                word("\"$TOKEN\""),
+                Token::Operator(Operator::LessLessLess),
+                word("x"),
+            ]
+        );
+    }
+
+    #[test]
+    fn clobber_is_one_operator() {
+        assert_eq!(
+            tokens("a >|f"),
+            vec![word("a"), Token::Operator(Operator::GreaterPipe), word("f")]
+        );
+        assert_eq!(
+            tokens("a > |f"),
+            vec![
+                word("a"),
+                Token::Operator(Operator::Greater),
+                Token::Operator(Operator::Pipe),
+                word("f"),
+            ]
+        );
+    }
+
+    #[test]
+    fn command_substitutions_are_kept_in_words() {
+        assert_eq!(
+            tokens("echo $(a; b | c)x `d; e` y"),
+            vec![
+                word("echo"),
+                word("$(a; b | c)x"),
+                word("`d; e`"),
+                word("y")
+            ]
+        );
+        // parentheses, quotes and inner substitutions are nested
+        assert_eq!(
+            tokens(r#"a $(b $(c ")") (d) ')') "$(e)""#),
+            vec![
+                word("a"),
+                word(r#"$(b $(c ")") (d) ')')"#),
+                word(r#""$(e)""#)
+            ]
+        );
+        assert_eq!(tokens(r"a `b \` c`"), vec![word("a"), word(r"`b \` c`")]);
+        assert!(matches!(
+            lex("a $(b (c)"),
+            Err(LexError::UnterminatedExpansion(')', 2))
+        ));
+        assert!(matches!(
+            lex("a `b"),
+            Err(LexError::UnterminatedQuote('`', 2))
+        ));
+    }
+
+    #[test]
+    fn digits_before_process_substitutions_are_words() {
+        assert_eq!(tokens("echo 2>(cat)"), vec![word("echo"), word("2>(cat)")]);
+        assert_eq!(tokens("cat 0<(echo)"), vec![word("cat"), word("0<(echo)")]);
+        assert_eq!(
+            tokens("echo 2>f"),
+            vec![
+                word("echo"),
+                Token::IoNumber(2),
+                Token::Operator(Operator::Greater),
+                word("f"),
+            ]
+        );
+    }
 }
@@ -1,40 +1,40 @@
 extern crate colored;
 extern crate nix;
 
+mod arith;
 mod complete;
 mod display;
 mod execute;
+mod expand;
+mod glob;
 mod lexer;
 mod parser;
+mod pattern;
 mod search;
+mod time;
 mod utils;
 
 use display::ReadEnum;
 use execute::ExecutionError;
-use nix::errno::Errno;
-use nix::sys::signal::SigHandler;
-use nix::sys::termios::Termios;
-use parser::ParseError;
-use std::io::{stdin, stdout, Error, Read, Write};
-use std::path::PathBuf;
-use std::process::exit;
-use utils::{Env, ErrorEnum};
+use utils::Env;
 
 fn main() {
     prepare();
     main_loop();
 }
 
-extern "C" fn sigint_handler_fn(_c: i32) {}
+extern "C" fn sigint_handler_fn(_c: i32) {
+    execute::INTERRUPTED.store(true, std::sync::atomic::Ordering::SeqCst);
+}
 extern "C" fn sigquit_handler_fn(_c: i32) {}
 
 fn prepare() {
     unsafe {
         use nix::sys::signal::*;
-        if let Err(_) = signal(Signal::SIGINT, SigHandler::Handler(sigint_handler_fn)) {
+        if signal(Signal::SIGINT, SigHandler::Handler(sigint_handler_fn)).is_err() {
             println!("SIGINT handler set failed");
         }
-        if let Err(_) = signal(Signal::SIGQUIT, SigHandler::Handler(sigquit_handler_fn)) {
+        if signal(Signal::SIGQUIT, SigHandler::Handler(sigquit_handler_fn)).is_err() {
             println!("SIGQUIT handler set failed");
         }
     }
@@ -45,20 +45,37 @@ fn prepare() {
 enum ExecuteResult {
     Success(i32),
     Empty,
-    Error,
+    // the status is 2 for syntax errors and 1 for other errors
+    Error(i32),
     Exit,
 }
 
+// commands in myshellrc are executed at startup, e.g. to define functions
+fn exec_rc(env: &mut Env) {
+    let Ok(script) = std::fs::read_to_string(&env.auto_exec_path) else {
+        return;
+    };
+    match parser::make_parse_tree_from_str(&script) {
+        Ok(stmt) => match execute::execute(stmt, env) {
+            Ok(_) | Err(ExecutionError::StatementIsEmpty) => {}
+            Err(ExecutionError::Exit) => std::process::exit(env.last_status),
+            Err(err) => println2!("{}", utils::ErrorEnum::ExecutionError(err)),
+        },
+        Err(err) => println2!("{}", err),
+    }
+}
+
 fn main_loop() {
     let mut env = Env::new();
+    exec_rc(&mut env);
     let mut display = display::Display::new();
     display.write_header(&env);
     loop {
         match display.get_enum(&env) {
             ReadEnum::Command(input) => {
-                let parse_result = parser::make_parse_tree_from_str(&input, &env);
+                let parse_result = parser::make_parse_tree_from_str(&input);
                 match match parse_result {
-                    Ok(commands) => match execute::execute(commands, &env) {
+                    Ok(commands) => match execute::execute(commands, &mut env) {
                         Ok(status) => {
                             // println!("status: {}", status);
                             ExecuteResult::Success(status)
@@ -70,19 +87,19 @@ fn main_loop() {
                         Err(ExecutionError::StatementIsEmpty) => ExecuteResult::Empty,
                         Err(err) => {
                             println2!("{}", utils::ErrorEnum::ExecutionError(err));
-                            ExecuteResult::Error
+                            ExecuteResult::Error(1)
                         }
                     },
                     Err(err) => {
                         println2!("{}", err);
-                        ExecuteResult::Error
+                        ExecuteResult::Error(2)
                     }
                 } {
                     ExecuteResult::Success(status) => {
                         env.push_history(input, status);
                     }
-                    ExecuteResult::Error => {
-                        env.push_history(input, -1);
+                    ExecuteResult::Error(status) => {
+                        env.push_history(input, status);
                     }
                     ExecuteResult::Empty => {}
                     ExecuteResult::Exit => {
@@ -1,81 +1,302 @@
 use crate::lexer;
-use crate::utils::{replace_tokens, Env, ErrorEnum};
-use lexer::{LexError, Operator, Token};
+use crate::utils::{is_name, ErrorEnum, Location};
+use lexer::{HereDoc, Operator, Span, Token};
+use std::fmt::{Display, Formatter};
 use thiserror::Error;
 
 /*
-   <statement> ::= <commands> [ ; <statement> ]?
+   <statement> ::= <commands> [ [ ; | \n ] <statement> ]?
    <commands>  ::= <commands2> [ & ]? | <epsilon>
-   <commands2> ::= <command> [ <operator> <commands2> ]?
-   <pipe>      ::= <command> [ < <str> ]? [ <pipe2> ]? [[ > <str> ] | [ 2> <str> ]]+
-   <pipe2>     ::= <command> [ | <pipe2> ]?
-   <command>   ::= [ <str> ]+
+   <commands2> ::= <pipeline> [ <operator> <commands2> ]?
+   <pipeline>  ::= [ time [ -p ]? | ! ]* <pipe>
+   <pipe>      ::= <stage> [ | <pipe> ]?
+   <stage>     ::= <command> | <compound> [ <redirect> ]* | <function>
+   <function>  ::= <name> ( ) [ \n ]* <compound> [ <redirect> ]*
+                 | function <name> [ ( ) ]? [ \n ]* <compound> [ <redirect> ]*
+   <compound>  ::= ( <statement> ) | { <statement> }
+                 | if <statement> then <statement> [ elif <statement> then <statement> ]*
+                   [ else <statement> ]? fi
+                 | [ while | until ] <statement> do <statement> done
+                 | for <name> [ [ \n ]* in <str>* ]? [ ; | \n ]* do <statement> done
+                 | for ((<expr>; <expr>; <expr>)) [ ; | \n ]* do <statement> done
+                 | case <str> [ \n ]* in [ \n ]* <case_item>* esac
+   <case_item> ::= [ ( ]? <str> [ | <str> ]* ) <statement> [ ;; | ;& | ;;& ]? [ \n ]*
+   <command>   ::= [ <str> | <redirect> ]+
+   <redirect>  ::= [ <fd> ]? [ < | > | >| | >> | <> ] <str>
+                 | [ <fd> ]? [ << | <<- ] <str> (followed by the body after the next newline)
+                 | [ <fd> ]? <<< <str>
+                 | [ <fd> ]? [ <& | >& ] [ <fd> | - ]
+                 | &> <str>
    <operator>  ::= "&&" | "||"
+   (newlines are allowed after "&&", "||" and "|")
+   <fd>        ::= [0-9]+
    <str>       ::= <char>+
    <char>      ::= any character
 */
 
-#[derive(Clone, Error, Debug)]
+#[derive(Clone, Error, Debug, PartialEq, Eq)]
 pub enum ParseError {
     #[error("parser does not reach the end of commands (finished at token {0})")]
     ParseFinished(usize),
     #[error("command is empty (at token {0})")]
     CommandIsEmpty(usize),
-    #[error("token is invalid (at token {0})")]
-    InvalidToken(usize),
-    #[error("redirected multi time (at token {0})")]
-    MultiRedirect(usize),
     #[error("redirected file is empty (at token {0})")]
     RedirectIsEmpty(usize),
+    #[error("file descriptor is invalid (at token {0})")]
+    InvalidFd(usize),
+    #[error("redirection is invalid (at token {0})")]
+    InvalidRedirect(usize),
+    #[error("missing \"{0}\" (at token {1})")]
+    MissingEnd(String, usize),
+    #[error("invalid variable name (at token {0})")]
+    InvalidName(usize),
+    #[error("invalid arithmetic for loop (at token {0})")]
+    InvalidArithFor(usize),
+    #[error("word is empty (at token {0})")]
+    WordIsEmpty(usize),
+    #[error("pattern is empty (at token {0})")]
+    PatternIsEmpty(usize),
+    #[error("function body is not a compound command (at token {0})")]
+    InvalidFunctionBody(usize),
+}
+
+#[derive(Clone, Debug)]
+pub enum RedirectKind {
+    // <
+    Input(String),
+    // >
+    Output(String),
+    // >|, which ignores noclobber
+    Clobber(String),
+    // >>
+    Append(String),
+    // <>
+    ReadWrite(String),
+    // << and <<-
+    HereDoc(HereDoc),
+    // <<<
+    HereString(String),
+    // <& and >&
+    DupInput(i32),
+    DupOutput(i32),
+    // <&- and >&-
+    CloseInput,
+    CloseOutput,
+}
+
+impl RedirectKind {
+    // the file name which is expanded before the redirection
+    pub fn target_mut(&mut self) -> Option<&mut String> {
+        match self {
+            RedirectKind::Input(s)
+            | RedirectKind::Output(s)
+            | RedirectKind::Clobber(s)
+            | RedirectKind::Append(s)
+            | RedirectKind::ReadWrite(s) => Some(s),
+            RedirectKind::HereDoc(_)
+            | RedirectKind::HereString(_)
+            | RedirectKind::DupInput(_)
+            | RedirectKind::DupOutput(_)
+            | RedirectKind::CloseInput
+            | RedirectKind::CloseOutput => None,
+        }
+    }
+}
+
+// redirections are applied to `fd` in the order of the command line
+#[derive(Clone, Debug)]
+pub struct Redirect {
+    pub fd: i32,
+    pub kind: RedirectKind,
+}
+
+impl Display for Redirect {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            RedirectKind::Input(path) => write!(f, "{}<{}", self.fd, path),
+            RedirectKind::Output(path) => write!(f, "{}>{}", self.fd, path),
+            RedirectKind::Clobber(path) => write!(f, "{}>|{}", self.fd, path),
+            RedirectKind::Append(path) => write!(f, "{}>>{}", self.fd, path),
+            RedirectKind::ReadWrite(path) => write!(f, "{}<>{}", self.fd, path),
+            RedirectKind::HereDoc(_) => write!(f, "{}<<EOF", self.fd),
+            RedirectKind::HereString(word) => write!(f, "{}<<<{}", self.fd, word),
+            RedirectKind::DupInput(fd) => write!(f, "{}<&{}", self.fd, fd),
+            RedirectKind::DupOutput(fd) => write!(f, "{}>&{}", self.fd, fd),
+            RedirectKind::CloseInput => write!(f, "{}<&-", self.fd),
+            RedirectKind::CloseOutput => write!(f, "{}>&-", self.fd),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Command {
+    // "NAME=value" before the command name
+    pub assignments: Vec<(String, String)>,
     pub str: Vec<String>,
+    pub redirects: Vec<Redirect>,
 }
 
-impl Command {
-    pub fn to_string(&self) -> String {
-        self.str
+impl Display for Command {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let assignments = self
+            .assignments
             .iter()
-            .skip(1)
-            .fold(self.str[0].clone(), |x, y| x + " " + y)
+            .map(|(name, value)| format!("{}={}", name, value));
+        let redirects = self.redirects.iter().map(|x| x.to_string());
+        // assignments or words may be missing
+        let parts = assignments
+            .chain(self.str.iter().cloned())
+            .chain(redirects)
+            .collect::<Vec<_>>();
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Compound {
+    // ( ... ), executed in a subshell
+    Subshell(Statement),
+    // { ...; }, executed in the current shell unless it is in a pipeline
+    Group(Statement),
+    // pairs of a condition and a body of "if" and "elif", and the body of "else"
+    If(Vec<(Statement, Statement)>, Option<Statement>),
+    // the condition and the body
+    While(Statement, Statement),
+    Until(Statement, Statement),
+    // the variable, the words (the positional parameters if None) and the body
+    For(String, Option<Vec<String>>, Statement),
+    // the initialization, the condition, the update and the body
+    ArithFor(String, String, String, Statement),
+    // the word, and the patterns, the body and the terminator (";;", ";&" or ";;&") of items
+    Case(String, Vec<(Vec<String>, Statement, Operator)>),
+}
+
+impl Display for Compound {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compound::Subshell(stmt) => write!(f, "({})", stmt),
+            Compound::Group(stmt) => write!(f, "{{ {}; }}", stmt),
+            Compound::If(branches, els) => {
+                for (i, (cond, body)) in branches.iter().enumerate() {
+                    let word = if i == 0 { "if" } else { "elif" };
+                    write!(f, "{} {}; then {}; ", word, cond, body)?;
+                }
+                if let Some(els) = els {
+                    write!(f, "else {}; ", els)?;
+                }
+                write!(f, "fi")
+            }
+            Compound::While(cond, body) => write!(f, "while {}; do {}; done", cond, body),
+            Compound::Until(cond, body) => write!(f, "until {}; do {}; done", cond, body),
+            Compound::For(name, words, body) => {
+                write!(f, "for {}", name)?;
+                if let Some(words) = words {
+                    write!(f, " in")?;
+                    for word in words {
+                        write!(f, " {}", word)?;
+                    }
+                }
+                write!(f, "; do {}; done", body)
+            }
+            Compound::ArithFor(init, cond, update, body) => {
+                write!(
+                    f,
+                    "for (({}; {}; {})); do {}; done",
+                    init, cond, update, body
+                )
+            }
+            Compound::Case(word, items) => {
+                write!(f, "case {} in", word)?;
+                for (patterns, body, end) in items {
+                    write!(f, " {})", patterns.join(" | "))?;
+                    // an empty body adds no space
+                    let body = body.to_string();
+                    if !body.is_empty() {
+                        write!(f, " {}", body)?;
+                    }
+                    write!(f, " {}", end)?;
+                }
+                write!(f, " esac")
+            }
+        }
+    }
+}
+
+// a command in a pipeline
+#[derive(Clone, Debug)]
+pub enum Stage {
+    Simple(Command),
+    // redirections are applied to the whole compound command
+    Compound(Compound, Vec<Redirect>),
+    // the name and the body, which is a compound command
+    Function(String, Box<Stage>),
+}
+
+impl Display for Stage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stage::Simple(command) => write!(f, "{}", command),
+            Stage::Compound(compound, redirects) => {
+                write!(f, "{}", compound)?;
+                for redirect in redirects {
+                    write!(f, " {}", redirect)?;
+                }
+                Ok(())
+            }
+            Stage::Function(name, body) => write!(f, "{}() {}", name, body),
+        }
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct Pipe {
-    pub command: Command,
+    pub command: Stage,
     pub tail: Option<Box<Pipe>>,
 }
 
-impl Pipe {
-    pub fn to_string(&self) -> String {
-        self.command.to_string() + &self.tail.as_ref().map_or("".to_string(), |x| x.to_string())
+impl Display for Pipe {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.command)?;
+        if let Some(tail) = &self.tail {
+            write!(f, " | {}", tail)?;
+        }
+        Ok(())
     }
 }
 
+// the output format of "time"
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeFormat {
+    // TIMEFORMAT
+    Default,
+    // time -p
+    Posix,
+}
+
 #[derive(Clone, Debug)]
 pub struct PipeBlock {
-    pub command: Command,
+    pub command: Stage,
     pub tail: Option<Pipe>,
-    pub from: Option<String>,
-    pub to: Option<String>,
-    pub to_err: Option<String>,
+    // "!" before the pipeline
+    pub negated: bool,
+    pub time: Option<TimeFormat>,
 }
 
-impl PipeBlock {
-    pub fn to_string(&self) -> String {
-        self.command.to_string()
-            + &self
-                .from
-                .as_ref()
-                .map_or("".to_string(), |x| "< ".to_owned() + &x)
-            + &self
-                .to
-                .as_ref()
-                .map_or("".to_string(), |x| "< ".to_owned() + &x)
-            + &self.tail.as_ref().map_or("".to_string(), |x| x.to_string())
+impl Display for PipeBlock {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.time {
+            Some(TimeFormat::Default) => write!(f, "time ")?,
+            Some(TimeFormat::Posix) => write!(f, "time -p ")?,
+            None => {}
+        }
+        if self.negated {
+            write!(f, "! ")?;
+        }
+        write!(f, "{}", self.command)?;
+        if let Some(tail) = &self.tail {
+            write!(f, " | {}", tail)?;
+        }
+        Ok(())
     }
 }
 
@@ -85,12 +306,13 @@ pub struct Commands {
     pub tail: Option<(lexer::Operator, Box<Commands>)>,
 }
 
-impl Commands {
-    pub fn to_string(&self) -> String {
-        match &self.tail {
-            None => self.head.to_string(),
-            Some((op, tail)) => self.head.to_string() + &op.to_string() + &tail.to_string(),
+impl Display for Commands {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.head)?;
+        if let Some((op, tail)) = &self.tail {
+            write!(f, " {} {}", op, tail)?;
         }
+        Ok(())
     }
 }
 
@@ -100,23 +322,87 @@ pub struct Statement {
     pub last_empty: bool,
 }
 
-impl Statement {
-    pub fn to_string(&self) -> String {
-        self.stmt.iter().fold("".to_string(), |x, (y, b)| {
-            x + "; " + &y.to_string() + (if *b { "&" } else { "" })
-        })
+impl Display for Statement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, (commands, background)) in self.stmt.iter().enumerate() {
+            if i != 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}", commands)?;
+            if *background {
+                write!(f, " &")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ParseError {
+    pub fn index(&self) -> usize {
+        match self {
+            ParseError::ParseFinished(i) => *i,
+            ParseError::CommandIsEmpty(i) => *i,
+            ParseError::RedirectIsEmpty(i) => *i,
+            ParseError::InvalidFd(i) => *i,
+            ParseError::InvalidRedirect(i) => *i,
+            ParseError::MissingEnd(_, i) => *i,
+            ParseError::InvalidName(i) => *i,
+            ParseError::InvalidArithFor(i) => *i,
+            ParseError::WordIsEmpty(i) => *i,
+            ParseError::PatternIsEmpty(i) => *i,
+            ParseError::InvalidFunctionBody(i) => *i,
+        }
+    }
+    pub fn expected(&self) -> String {
+        match self {
+            ParseError::ParseFinished(_) => "expected \";\" or a newline".to_string(),
+            ParseError::CommandIsEmpty(_) => "expected a command".to_string(),
+            ParseError::RedirectIsEmpty(_) => "expected a file name after this".to_string(),
+            ParseError::InvalidFd(_) => "expected a file descriptor or \"-\"".to_string(),
+            ParseError::InvalidRedirect(_) => "expected a redirection operator".to_string(),
+            ParseError::MissingEnd(end, _) => format!("expected \"{}\"", end),
+            ParseError::InvalidName(_) => "expected a variable name".to_string(),
+            ParseError::InvalidArithFor(_) => {
+                "expected three expressions separated by \";\"".to_string()
+            }
+            ParseError::WordIsEmpty(_) => "expected a word".to_string(),
+            ParseError::PatternIsEmpty(_) => "expected a pattern".to_string(),
+            ParseError::InvalidFunctionBody(_) => {
+                "expected a compound command like \"{ ...; }\"".to_string()
+            }
+        }
     }
 }
 
-pub fn make_parse_tree_from_str(s: &str, env: &Env) -> Result<Statement, ErrorEnum> {
+pub fn make_parse_tree_from_str(s: &str) -> Result<Statement, ErrorEnum> {
     match lexer::lex(s) {
-        Ok(tokens) => make_parse_tree_from_tokens(tokens, env),
-        Err(err) => Err(ErrorEnum::LexError(err)),
+        Ok(tokens) => {
+            let (tokens, spans): (Vec<_>, Vec<_>) = tokens.into_iter().unzip();
+            make_parse_tree_from_tokens(tokens).map_err(|err| match err {
+                ErrorEnum::ParseError(parse_err) => {
+                    // errors at the end of tokens point the end of input
+                    let len = s.chars().count();
+                    let span = spans
+                        .get(parse_err.index())
+                        .cloned()
+                        .unwrap_or(Span::new(len, len + 1));
+                    let location = Location::new(s, span, parse_err.expected());
+                    ErrorEnum::Located(Box::new(ErrorEnum::ParseError(parse_err)), location)
+                }
+                err => err,
+            })
+        }
+        Err(err) => {
+            let location = Location::new(s, err.span(), err.expected());
+            Err(ErrorEnum::Located(
+                Box::new(ErrorEnum::LexError(err)),
+                location,
+            ))
+        }
     }
 }
 
-pub fn make_parse_tree_from_tokens(tokens: Vec<Token>, env: &Env) -> Result<Statement, ErrorEnum> {
-    let tokens = replace_tokens(tokens, env);
+pub fn make_parse_tree_from_tokens(tokens: Vec<Token>) -> Result<Statement, ErrorEnum> {
     let mut i = 0;
     match parse_statement(&tokens, &mut i) {
         Ok(stmt) => {
@@ -130,31 +416,395 @@ pub fn make_parse_tree_from_tokens(tokens: Vec<Token>, env: &Env) -> Result<Stat
     }
 }
 
-fn parse_command(tokens: &Vec<lexer::Token>, l: &mut usize) -> Result<Command, ParseError> {
+// returns true if `s` is an unfinished command which is continued on the next line
+pub fn is_incomplete(s: &str) -> bool {
+    match lexer::lex(s) {
+        Ok(tokens) => {
+            let tokens = tokens
+                .into_iter()
+                .map(|(token, _)| token)
+                .collect::<Vec<_>>();
+            let mut i = 0;
+            match parse_statement(&tokens, &mut i) {
+                Err(ParseError::CommandIsEmpty(i)) => i != 0 && i == tokens.len(),
+                Err(
+                    ParseError::MissingEnd(_, i)
+                    | ParseError::InvalidName(i)
+                    | ParseError::WordIsEmpty(i)
+                    | ParseError::PatternIsEmpty(i),
+                ) => i == tokens.len(),
+                _ => false,
+            }
+        }
+        Err(err) => err.is_incomplete(),
+    }
+}
+
+fn skip_newlines(tokens: &[lexer::Token], l: &mut usize) {
+    while let Some(Token::Operator(Operator::NewLine)) = tokens.get(*l) {
+        *l += 1;
+    }
+}
+
+fn parse_redirect(tokens: &[lexer::Token], l: &mut usize) -> Result<Vec<Redirect>, ParseError> {
+    let fd = if let Token::IoNumber(fd) = tokens[*l] {
+        *l += 1;
+        Some(fd)
+    } else {
+        None
+    };
+    let op = match tokens.get(*l) {
+        Some(Token::Operator(op)) => op.clone(),
+        _ => return Err(ParseError::InvalidRedirect(*l)),
+    };
+    if let Operator::LessLess | Operator::LessLessDash = op {
+        return match tokens.get(*l + 1) {
+            Some(Token::HereDoc(doc)) => {
+                *l += 2;
+                Ok(vec![Redirect {
+                    fd: fd.unwrap_or(0),
+                    kind: RedirectKind::HereDoc(doc.clone()),
+                }])
+            }
+            _ => Err(ParseError::RedirectIsEmpty(*l)),
+        };
+    }
+    let target = match tokens.get(*l + 1) {
+        Some(Token::String(s)) => s.clone(),
+        _ => return Err(ParseError::RedirectIsEmpty(*l)),
+    };
+    // the direction of "<&" and ">&" is kept only to show the redirection
+    let target_fd = |input: bool| match (target.as_str(), input) {
+        ("-", true) => Ok(RedirectKind::CloseInput),
+        ("-", false) => Ok(RedirectKind::CloseOutput),
+        (s, _) => s
+            .parse()
+            .map(if input {
+                RedirectKind::DupInput
+            } else {
+                RedirectKind::DupOutput
+            })
+            .map_err(|_| ParseError::InvalidFd(*l + 1)),
+    };
+    let res = match op {
+        Operator::Less => vec![(0, RedirectKind::Input(target.clone()))],
+        Operator::LessAnd => vec![(0, target_fd(true)?)],
+        Operator::LessGreater => vec![(0, RedirectKind::ReadWrite(target.clone()))],
+        Operator::LessLessLess => vec![(0, RedirectKind::HereString(target.clone()))],
+        Operator::Greater => vec![(1, RedirectKind::Output(target.clone()))],
+        Operator::GreaterPipe => vec![(1, RedirectKind::Clobber(target.clone()))],
+        Operator::GreaterGreater => vec![(1, RedirectKind::Append(target.clone()))],
+        Operator::GreaterAnd => vec![(1, target_fd(false)?)],
+        // "&> file" is the same as "> file 2>&1"
+        Operator::AndGreater => vec![
+            (1, RedirectKind::Output(target.clone())),
+            (2, RedirectKind::DupOutput(1)),
+        ],
+        _ => return Err(ParseError::InvalidRedirect(*l)),
+    };
+    *l += 2;
+    Ok(res
+        .into_iter()
+        .map(|(default_fd, kind)| Redirect {
+            fd: fd.unwrap_or(default_fd),
+            kind,
+        })
+        .collect())
+}
+
+fn parse_command(tokens: &[lexer::Token], l: &mut usize) -> Result<Command, ParseError> {
+    let mut assignments = Vec::new();
     let mut v = Vec::new();
+    let mut redirects = Vec::new();
     while *l < tokens.len() {
         match &tokens[*l] {
-            Token::Operator(_) => {
+            Token::IoNumber(_) => {
+                redirects.extend(parse_redirect(tokens, l)?);
+            }
+            Token::Operator(op) if op.is_redirect() => {
+                redirects.extend(parse_redirect(tokens, l)?);
+            }
+            Token::Operator(_) | Token::HereDoc(_) => {
                 break;
             }
             Token::String(s) => {
-                v.push(s.clone());
+                // assignments are recognized only before the command name
+                match s
+                    .split_once('=')
+                    .filter(|(name, _)| v.is_empty() && is_name(name))
+                {
+                    Some((name, value)) => assignments.push((name.to_string(), value.to_string())),
+                    None => v.push(s.clone()),
+                }
+                *l += 1;
             }
         }
-        *l += 1;
     }
-    if v.is_empty() {
+    if v.is_empty() && redirects.is_empty() && assignments.is_empty() {
         Err(ParseError::CommandIsEmpty(*l))
     } else {
-        Ok(Command { str: v })
+        Ok(Command {
+            assignments,
+            str: v,
+            redirects,
+        })
+    }
+}
+
+// reserved words are recognized only at the position of a command name
+fn is_reserved(token: &Token, word: &str) -> bool {
+    match token {
+        Token::String(s) => s == word,
+        Token::Operator(Operator::RightParen) => word == ")",
+        _ => false,
+    }
+}
+
+// words which end the statement in a compound command
+const CLOSING_WORDS: [&str; 9] = ["}", ")", "then", "elif", "else", "fi", "do", "done", "esac"];
+
+fn is_closing(token: &Token) -> bool {
+    is_case_end(token) || CLOSING_WORDS.iter().any(|word| is_reserved(token, word))
+}
+
+fn is_case_end(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Operator(
+            Operator::DoubleSemiColon | Operator::SemiColonAnd | Operator::DoubleSemiColonAnd
+        )
+    )
+}
+
+// parses the statement in a compound command, and returns it with the word which ends it.
+// the last one of `ends` is shown when none of them is found
+fn parse_body<'a>(
+    tokens: &[lexer::Token],
+    l: &mut usize,
+    ends: &[&'a str],
+) -> Result<(Statement, &'a str), ParseError> {
+    let stmt = parse_statement(tokens, l)?;
+    if stmt.stmt.is_empty() || (*l == tokens.len() && stmt.last_empty) {
+        return Err(ParseError::CommandIsEmpty(*l));
+    }
+    match tokens
+        .get(*l)
+        .and_then(|token| ends.iter().find(|end| is_reserved(token, end)))
+    {
+        Some(end) => {
+            *l += 1;
+            Ok((stmt, end))
+        }
+        None => Err(ParseError::MissingEnd(ends.last().unwrap().to_string(), *l)),
+    }
+}
+
+fn parse_if(tokens: &[lexer::Token], l: &mut usize) -> Result<Compound, ParseError> {
+    let mut branches = Vec::new();
+    loop {
+        let (cond, _) = parse_body(tokens, l, &["then"])?;
+        let (body, end) = parse_body(tokens, l, &["elif", "else", "fi"])?;
+        branches.push((cond, body));
+        match end {
+            "elif" => continue,
+            "else" => {
+                let (els, _) = parse_body(tokens, l, &["fi"])?;
+                return Ok(Compound::If(branches, Some(els)));
+            }
+            _ => return Ok(Compound::If(branches, None)),
+        }
+    }
+}
+
+// parses "do <statement> done" after the header of a for loop
+fn parse_do(tokens: &[lexer::Token], l: &mut usize) -> Result<Statement, ParseError> {
+    while let Some(Token::Operator(Operator::SemiColon | Operator::NewLine)) = tokens.get(*l) {
+        *l += 1;
+    }
+    match tokens.get(*l) {
+        Some(token) if is_reserved(token, "do") => *l += 1,
+        _ => return Err(ParseError::MissingEnd("do".to_string(), *l)),
+    }
+    Ok(parse_body(tokens, l, &["done"])?.0)
+}
+
+fn parse_for(tokens: &[lexer::Token], l: &mut usize) -> Result<Compound, ParseError> {
+    let word = match tokens.get(*l) {
+        Some(Token::String(s)) => s.clone(),
+        _ => return Err(ParseError::InvalidName(*l)),
+    };
+    if let Some(arith) = word.strip_prefix("((").and_then(|x| x.strip_suffix("))")) {
+        let exprs = arith
+            .split(';')
+            .map(|x| x.trim().to_string())
+            .collect::<Vec<_>>();
+        if exprs.len() != 3 {
+            return Err(ParseError::InvalidArithFor(*l));
+        }
+        *l += 1;
+        let body = parse_do(tokens, l)?;
+        let [init, cond, update]: [String; 3] = exprs.try_into().unwrap();
+        return Ok(Compound::ArithFor(init, cond, update, body));
+    }
+    if !is_name(&word) {
+        return Err(ParseError::InvalidName(*l));
+    }
+    *l += 1;
+    skip_newlines(tokens, l);
+    let mut words = None;
+    if tokens.get(*l).is_some_and(|x| is_reserved(x, "in")) {
+        *l += 1;
+        let mut v = Vec::new();
+        while let Some(Token::String(s)) = tokens.get(*l) {
+            v.push(s.clone());
+            *l += 1;
+        }
+        words = Some(v);
     }
+    let body = parse_do(tokens, l)?;
+    Ok(Compound::For(word, words, body))
 }
 
-fn parse_pipe(tokens: &Vec<lexer::Token>, l: &mut usize) -> Result<Pipe, ParseError> {
-    let command = parse_command(tokens, l)?;
+fn parse_case(tokens: &[lexer::Token], l: &mut usize) -> Result<Compound, ParseError> {
+    let word = match tokens.get(*l) {
+        Some(Token::String(s)) => s.clone(),
+        _ => return Err(ParseError::WordIsEmpty(*l)),
+    };
+    *l += 1;
+    skip_newlines(tokens, l);
+    match tokens.get(*l) {
+        Some(token) if is_reserved(token, "in") => *l += 1,
+        _ => return Err(ParseError::MissingEnd("in".to_string(), *l)),
+    }
+    let mut items = Vec::new();
+    loop {
+        skip_newlines(tokens, l);
+        match tokens.get(*l) {
+            Some(token) if is_reserved(token, "esac") => {
+                *l += 1;
+                return Ok(Compound::Case(word, items));
+            }
+            Some(Token::Operator(Operator::LeftParen)) => *l += 1,
+            None => return Err(ParseError::MissingEnd("esac".to_string(), *l)),
+            _ => {}
+        }
+        let mut patterns = Vec::new();
+        loop {
+            match tokens.get(*l) {
+                Some(Token::String(s)) => patterns.push(s.clone()),
+                _ => return Err(ParseError::PatternIsEmpty(*l)),
+            }
+            *l += 1;
+            match tokens.get(*l) {
+                Some(Token::Operator(Operator::Pipe)) => *l += 1,
+                Some(Token::Operator(Operator::RightParen)) => {
+                    *l += 1;
+                    break;
+                }
+                _ => return Err(ParseError::MissingEnd(")".to_string(), *l)),
+            }
+        }
+        // the body may be empty
+        let body = parse_statement(tokens, l)?;
+        let end = match tokens.get(*l) {
+            Some(Token::Operator(op)) if is_case_end(&tokens[*l]) => {
+                *l += 1;
+                op.clone()
+            }
+            // ";;" of the last item can be omitted
+            Some(token) if is_reserved(token, "esac") => Operator::DoubleSemiColon,
+            _ => return Err(ParseError::MissingEnd("esac".to_string(), *l)),
+        };
+        items.push((patterns, body, end));
+    }
+}
+
+// "name() body" or "function name body"
+fn parse_function(tokens: &[lexer::Token], l: &mut usize) -> Result<Stage, ParseError> {
+    let name = match tokens.get(*l) {
+        Some(Token::String(s)) if is_name(s) => s.clone(),
+        _ => return Err(ParseError::InvalidName(*l)),
+    };
+    *l += 1;
+    if let Some(Token::Operator(Operator::LeftParen)) = tokens.get(*l) {
+        *l += 1;
+        match tokens.get(*l) {
+            Some(Token::Operator(Operator::RightParen)) => *l += 1,
+            _ => return Err(ParseError::MissingEnd(")".to_string(), *l)),
+        }
+    }
+    skip_newlines(tokens, l);
+    let st = *l;
+    match parse_stage(tokens, l)? {
+        body @ Stage::Compound(..) => Ok(Stage::Function(name, Box::new(body))),
+        _ => Err(ParseError::InvalidFunctionBody(st)),
+    }
+}
+
+fn parse_stage(tokens: &[lexer::Token], l: &mut usize) -> Result<Stage, ParseError> {
+    let compound = match tokens.get(*l) {
+        Some(Token::Operator(Operator::LeftParen)) => {
+            *l += 1;
+            Compound::Subshell(parse_body(tokens, l, &[")"])?.0)
+        }
+        Some(token) if is_reserved(token, "{") => {
+            *l += 1;
+            Compound::Group(parse_body(tokens, l, &["}"])?.0)
+        }
+        Some(token) if is_reserved(token, "if") => {
+            *l += 1;
+            parse_if(tokens, l)?
+        }
+        Some(token) if is_reserved(token, "case") => {
+            *l += 1;
+            parse_case(tokens, l)?
+        }
+        Some(token) if is_reserved(token, "for") => {
+            *l += 1;
+            parse_for(tokens, l)?
+        }
+        Some(token) if is_reserved(token, "while") || is_reserved(token, "until") => {
+            *l += 1;
+            let (cond, _) = parse_body(tokens, l, &["do"])?;
+            let (body, _) = parse_body(tokens, l, &["done"])?;
+            if is_reserved(token, "while") {
+                Compound::While(cond, body)
+            } else {
+                Compound::Until(cond, body)
+            }
+        }
+        Some(token) if is_closing(token) => return Err(ParseError::CommandIsEmpty(*l)),
+        Some(token) if is_reserved(token, "function") => {
+            *l += 1;
+            return parse_function(tokens, l);
+        }
+        Some(Token::String(_))
+            if matches!(
+                tokens.get(*l + 1),
+                Some(Token::Operator(Operator::LeftParen))
+            ) =>
+        {
+            return parse_function(tokens, l);
+        }
+        _ => return Ok(Stage::Simple(parse_command(tokens, l)?)),
+    };
+    let mut redirects = Vec::new();
+    while let Some(token) = tokens.get(*l) {
+        match token {
+            Token::IoNumber(_) => redirects.extend(parse_redirect(tokens, l)?),
+            Token::Operator(op) if op.is_redirect() => redirects.extend(parse_redirect(tokens, l)?),
+            _ => break,
+        }
+    }
+    Ok(Stage::Compound(compound, redirects))
+}
+
+fn parse_pipe(tokens: &[lexer::Token], l: &mut usize) -> Result<Pipe, ParseError> {
+    let command = parse_stage(tokens, l)?;
     if *l < tokens.len() {
         if let Token::Operator(Operator::Pipe) = tokens[*l] {
             *l += 1;
+            skip_newlines(tokens, l);
             let tail = parse_pipe(tokens, l)?;
             return Ok(Pipe {
                 command,
@@ -168,110 +818,40 @@ fn parse_pipe(tokens: &Vec<lexer::Token>, l: &mut usize) -> Result<Pipe, ParseEr
     })
 }
 
-fn parse_redirection(
-    tokens: &Vec<lexer::Token>,
-    l: &mut usize,
-    to: &mut Option<String>,
-    to_err: &mut Option<String>,
-) -> Option<ParseError> {
-    for _ in 0..2 {
-        if *l < tokens.len() {
-            if let Token::Operator(Operator::Greater) = &tokens[*l] {
-                if *l + 1 == tokens.len() {
-                    return Some(ParseError::RedirectIsEmpty(*l));
-                }
-                if let Token::String(s) = &tokens[*l + 1] {
-                    if to.is_some() {
-                        return Some(ParseError::MultiRedirect(*l));
-                    }
-                    *to = Some(s.clone());
-                    *l += 2;
-                } else {
-                    return Some(ParseError::RedirectIsEmpty(*l));
-                }
-            } else if let Token::Operator(Operator::ErrorRedirect) = &tokens[*l] {
-                if *l + 1 == tokens.len() {
-                    return Some(ParseError::RedirectIsEmpty(*l));
-                }
-                if let Token::String(s) = &tokens[*l + 1] {
-                    if to_err.is_some() {
-                        return Some(ParseError::MultiRedirect(*l));
-                    }
-                    *to_err = Some(s.clone());
-                    *l += 2;
-                } else {
-                    return Some(ParseError::RedirectIsEmpty(*l));
-                }
-            }
-        }
-    }
-    None
-}
-
-fn parse_pipe_block(tokens: &Vec<lexer::Token>, l: &mut usize) -> Result<PipeBlock, ParseError> {
-    let command = parse_command(tokens, l)?;
-    let mut from = None;
-    let mut to = None;
-    let mut to_err = None;
-    if *l < tokens.len() {
-        if let Token::Operator(Operator::Less) = &tokens[*l] {
-            if *l + 1 == tokens.len() {
-                return Err(ParseError::RedirectIsEmpty(*l));
-            }
-            if let Token::String(s) = &tokens[*l + 1] {
-                from = Some(s.clone());
-                *l += 2;
-            } else {
-                return Err(ParseError::RedirectIsEmpty(*l));
+fn parse_pipe_block(tokens: &[lexer::Token], l: &mut usize) -> Result<PipeBlock, ParseError> {
+    // "time" and "!" are reserved only before the first stage
+    let mut negated = false;
+    let mut time = None;
+    while let Some(token) = tokens.get(*l) {
+        if is_reserved(token, "!") {
+            negated = !negated;
+        } else if is_reserved(token, "time") && time.is_none() {
+            time = Some(TimeFormat::Default);
+            if tokens.get(*l + 1).is_some_and(|x| is_reserved(x, "-p")) {
+                time = Some(TimeFormat::Posix);
+                *l += 1;
             }
+        } else {
+            break;
         }
+        *l += 1;
     }
-    if let Some(err) = parse_redirection(tokens, l, &mut to, &mut to_err) {
-        return Err(err);
-    }
-    if to.is_some() || to_err.is_some() {
-        return Ok(PipeBlock {
-            command,
-            tail: None,
-            from,
-            to,
-            to_err,
-        });
-    }
-    if *l == tokens.len() {
-        return Ok(PipeBlock {
-            command,
-            tail: None,
-            from,
-            to,
-            to_err,
-        });
-    }
-    if let Token::Operator(Operator::Pipe) = tokens[*l] {
+    let command = parse_stage(tokens, l)?;
+    let mut tail = None;
+    if let Some(Token::Operator(Operator::Pipe)) = tokens.get(*l) {
         *l += 1;
-        let pipe = parse_pipe(tokens, l)?;
-        if let Some(err) = parse_redirection(tokens, l, &mut to, &mut to_err) {
-            return Err(err);
-        }
-        Ok(PipeBlock {
-            command,
-            tail: Some(pipe),
-            from,
-            to,
-            to_err,
-        })
-    } else {
-        Ok(PipeBlock {
-            command,
-            tail: None,
-            from,
-            to,
-            to_err,
-        })
+        skip_newlines(tokens, l);
+        tail = Some(parse_pipe(tokens, l)?);
     }
+    Ok(PipeBlock {
+        command,
+        tail,
+        negated,
+        time,
+    })
 }
 
-fn parse_commands(tokens: &Vec<lexer::Token>, l: &mut usize) -> Result<Commands, ParseError> {
+fn parse_commands(tokens: &[lexer::Token], l: &mut usize) -> Result<Commands, ParseError> {
     let head = parse_pipe_block(tokens, l)?;
     if *l == tokens.len() {
         Ok(Commands { head, tail: None })
@@ -279,6 +859,7 @@ fn parse_commands(tokens: &Vec<lexer::Token>, l: &mut usize) -> Result<Commands,
         match tokens[*l] {
             Token::Operator(Operator::AndAnd) => {
                 *l += 1;
+                skip_newlines(tokens, l);
                 let tail = parse_commands(tokens, l)?;
                 Ok(Commands {
                     head,
@@ -287,6 +868,7 @@ fn parse_commands(tokens: &Vec<lexer::Token>, l: &mut usize) -> Result<Commands,
             }
             Token::Operator(Operator::OrOr) => {
                 *l += 1;
+                skip_newlines(tokens, l);
                 let tail = parse_commands(tokens, l)?;
                 Ok(Commands {
                     head,
@@ -298,15 +880,18 @@ fn parse_commands(tokens: &Vec<lexer::Token>, l: &mut usize) -> Result<Commands,
     }
 }
 
-fn parse_statement(tokens: &Vec<lexer::Token>, l: &mut usize) -> Result<Statement, ParseError> {
+fn parse_statement(tokens: &[lexer::Token], l: &mut usize) -> Result<Statement, ParseError> {
     let mut stmt = Vec::new();
     let mut last_empty = true;
     while *l < tokens.len() {
-        if let Token::Operator(Operator::SemiColon) = tokens[*l] {
+        if let Token::Operator(Operator::SemiColon | Operator::NewLine) = tokens[*l] {
             *l += 1;
             last_empty = true;
             continue;
         }
+        if is_closing(&tokens[*l]) {
+            break;
+        }
         let commands = parse_commands(tokens, l)?;
         let background = if *l < tokens.len() {
             if let Token::Operator(Operator::And) = tokens[*l] {
@@ -320,6 +905,368 @@ fn parse_statement(tokens: &Vec<lexer::Token>, l: &mut usize) -> Result<Statemen
         };
         stmt.push((commands, background));
         last_empty = false;
+        // commands must be separated
+        match tokens.get(*l) {
+            None | Some(Token::Operator(Operator::SemiColon | Operator::NewLine)) => {}
+            Some(token) if background || is_closing(token) => {}
+            _ => return Err(ParseError::ParseFinished(*l)),
+        }
     }
     Ok(Statement { stmt, last_empty })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> String {
+        make_parse_tree_from_str(s).unwrap().to_string()
+    }
+
+    fn parse_error(s: &str) -> ParseError {
+        let tokens = lexer::lex(s).unwrap().into_iter().map(|(x, _)| x).collect();
+        match make_parse_tree_from_tokens(tokens) {
+            Err(ErrorEnum::ParseError(err)) => err,
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn unfinished_input_is_incomplete() {
+        for s in [
+            "echo a \\",
+            "echo 'a",
+            "echo \"a",
+            "a &&",
+            "a ||",
+            "a |",
+            "a |\n",
+        ] {
+            assert!(is_incomplete(s), "{:?}", s);
+        }
+        for s in [
+            "", "echo a", "echo a;", "a && b", "&& a", "a | | b", "echo 'a'",
+        ] {
+            assert!(!is_incomplete(s), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn continued_lines_are_parsed_as_one_command() {
+        for s in ["a &&\nb", "a |\n\nb", "echo a \\\nb", "echo 'a\nb'"] {
+            assert!(make_parse_tree_from_str(s).is_ok(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn errors_are_located_at_the_bad_token() {
+        match make_parse_tree_from_str("echo a | | b") {
+            Err(ErrorEnum::Located(err, location)) => {
+                assert!(matches!(
+                    *err,
+                    ErrorEnum::ParseError(ParseError::CommandIsEmpty(3))
+                ));
+                assert_eq!((location.column, location.width), (9, 1));
+                assert_eq!(location.hint, "expected a command");
+            }
+            res => panic!("{:?}", res),
+        }
+        match make_parse_tree_from_str("echo 'a") {
+            Err(ErrorEnum::Located(err, location)) => {
+                assert!(matches!(*err, ErrorEnum::LexError(_)));
+                assert_eq!(location.column, 5);
+            }
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn redirections_are_kept_in_order() {
+        assert_eq!(
+            parse("cmd >out 2>&1 3<in 4>>log <>rw"),
+            "cmd 1>out 2>&1 3<in 4>>log 0<>rw"
+        );
+        assert_eq!(parse("cmd &>log"), "cmd 1>log 2>&1");
+        assert_eq!(parse("2>err cmd a"), "cmd a 2>err");
+    }
+
+    #[test]
+    fn duplications_keep_their_direction() {
+        let s = "cmd 0<&3 1>&2 4<&- 5>&-";
+        assert_eq!(parse(s), s);
+        assert_eq!(parse(&parse("cmd <&3 >&-")), "cmd 0<&3 1>&-");
+    }
+
+    #[test]
+    fn invalid_redirections() {
+        assert_eq!(parse_error("cmd >"), ParseError::RedirectIsEmpty(1));
+        assert_eq!(parse_error("cmd > ;"), ParseError::RedirectIsEmpty(1));
+        assert_eq!(parse_error("cmd >&x"), ParseError::InvalidFd(2));
+    }
+
+    #[test]
+    fn heredocs_are_redirections() {
+        assert_eq!(parse("cat <<E 3<<-F\na\nE\nb\nF"), "cat 0<<EOF 3<<EOF");
+        assert!(is_incomplete("cat <<E\na"));
+        assert!(!is_incomplete("cat <<E\na\nE"));
+    }
+
+    #[test]
+    fn here_strings() {
+        assert_eq!(parse("cat <<< 'a b' 3<<<c"), "cat 0<<<'a b' 3<<<c");
+        assert_eq!(parse_error("cat <<<"), ParseError::RedirectIsEmpty(1));
+    }
+
+    #[test]
+    fn clobber_redirections() {
+        assert_eq!(parse("echo a >|f"), "echo a 1>|f");
+        assert_eq!(parse("echo a 2>|f"), "echo a 2>|f");
+    }
+
+    #[test]
+    fn digits_before_process_substitutions_are_not_fds() {
+        assert_eq!(parse("echo 2>(cat)"), "echo 2>(cat)");
+        assert_eq!(parse("cat 1<(echo hi) 2>&1"), "cat 1<(echo hi) 2>&1");
+        // an fd must be followed by a redirection
+        let tokens = vec![
+            Token::String("echo".to_string()),
+            Token::IoNumber(2),
+            Token::String("x".to_string()),
+        ];
+        assert!(matches!(
+            make_parse_tree_from_tokens(tokens),
+            Err(ErrorEnum::ParseError(ParseError::InvalidRedirect(2)))
+        ));
+        let tokens = vec![Token::String("echo".to_string()), Token::IoNumber(2)];
+        assert!(matches!(
+            make_parse_tree_from_tokens(tokens),
+            Err(ErrorEnum::ParseError(ParseError::InvalidRedirect(2)))
+        ));
+    }
+
+    #[test]
+    fn subshells_and_groups() {
+        assert_eq!(parse("(cd a && make)"), "(cd a && make)");
+        assert_eq!(parse("(a; (b))"), "(a; (b))");
+        assert_eq!(parse("{ a; b; } >log"), "{ a; b; } 1>log");
+        assert_eq!(parse("{ a\nb\n}"), "{ a; b; }");
+        assert_eq!(parse("(a) | { b; } &"), "(a) | { b; } &");
+        assert_eq!(parse("x; (a)& y"), "x; (a) &; y");
+        // a brace is a reserved word only at the head of a command
+        assert_eq!(parse("echo { a }"), "echo { a }");
+        assert_eq!(parse("{ echo }; }"), "{ echo }; }");
+    }
+
+    #[test]
+    fn invalid_subshells_and_groups() {
+        assert_eq!(
+            parse_error("(a"),
+            ParseError::MissingEnd(")".to_string(), 2)
+        );
+        assert_eq!(
+            parse_error("{ a"),
+            ParseError::MissingEnd("}".to_string(), 2)
+        );
+        assert_eq!(
+            parse_error("{ a }"),
+            ParseError::MissingEnd("}".to_string(), 3)
+        );
+        assert_eq!(parse_error("()"), ParseError::CommandIsEmpty(1));
+        assert_eq!(parse_error("(a) b"), ParseError::ParseFinished(3));
+    }
+
+    #[test]
+    fn if_statements() {
+        assert_eq!(parse("if a; then b; fi"), "if a; then b; fi");
+        assert_eq!(
+            parse("if a\nthen\n  b\nelif c; d; then e\nelse f; fi"),
+            "if a; then b; elif c; d; then e; else f; fi"
+        );
+        assert_eq!(
+            parse("if a; then b; fi >out && c"),
+            "if a; then b; fi 1>out && c"
+        );
+        assert_eq!(
+            parse("if if a; then b; fi; then c; fi"),
+            "if if a; then b; fi; then c; fi"
+        );
+        // reserved words are words elsewhere
+        assert_eq!(parse("echo if then fi"), "echo if then fi");
+    }
+
+    #[test]
+    fn invalid_if_statements() {
+        assert_eq!(
+            parse_error("if a; fi"),
+            ParseError::MissingEnd("then".to_string(), 3)
+        );
+        assert_eq!(
+            parse_error("if a; then b"),
+            ParseError::MissingEnd("fi".to_string(), 5)
+        );
+        assert_eq!(parse_error("if; then b; fi"), ParseError::CommandIsEmpty(2));
+        assert_eq!(parse_error("if a; then fi"), ParseError::CommandIsEmpty(4));
+        assert_eq!(parse_error("then"), ParseError::ParseFinished(0));
+    }
+
+    #[test]
+    fn while_and_until_loops() {
+        assert_eq!(parse("while a; do b; done"), "while a; do b; done");
+        assert_eq!(
+            parse("until a\ndo\n  b; c\ndone | d"),
+            "until a; do b; c; done | d"
+        );
+        assert_eq!(
+            parse("while a; b; do while c; do break 2; done; done <in"),
+            "while a; b; do while c; do break 2; done; done 0<in"
+        );
+    }
+
+    #[test]
+    fn invalid_loops() {
+        assert_eq!(
+            parse_error("while a; done"),
+            ParseError::MissingEnd("do".to_string(), 3)
+        );
+        assert_eq!(
+            parse_error("until a; do b"),
+            ParseError::MissingEnd("done".to_string(), 5)
+        );
+        assert_eq!(
+            parse_error("while a; do done"),
+            ParseError::CommandIsEmpty(4)
+        );
+    }
+
+    #[test]
+    fn for_loops() {
+        assert_eq!(
+            parse("for x in a b; do c $x; done"),
+            "for x in a b; do c $x; done"
+        );
+        assert_eq!(parse("for x\ndo c; done"), "for x; do c; done");
+        assert_eq!(parse("for x in; do c; done"), "for x in; do c; done");
+        assert_eq!(
+            parse("for x in a\ndo for y; do c; done; done"),
+            "for x in a; do for y; do c; done; done"
+        );
+        assert_eq!(
+            parse("for ((i = 0; i < 3; i++)); do c; done"),
+            "for ((i = 0; i < 3; i++)); do c; done"
+        );
+        assert_eq!(
+            parse("for ((i=0, j=9; i<j; i++, j--)) do c; done"),
+            "for ((i=0, j=9; i<j; i++, j--)); do c; done"
+        );
+        assert_eq!(parse("for ((;;)); do c; done"), "for ((; ; )); do c; done");
+    }
+
+    #[test]
+    fn invalid_for_loops() {
+        assert_eq!(
+            parse_error("for 1x in a; do b; done"),
+            ParseError::InvalidName(1)
+        );
+        assert_eq!(parse_error("for; do b; done"), ParseError::InvalidName(1));
+        assert_eq!(
+            parse_error("for x in a b"),
+            ParseError::MissingEnd("do".to_string(), 5)
+        );
+        assert_eq!(
+            parse_error("for ((i; j)); do b; done"),
+            ParseError::InvalidArithFor(1)
+        );
+    }
+
+    #[test]
+    fn case_statements() {
+        assert_eq!(
+            parse("case $x in a|b) c;; (*.rs) d; e;& *) f;;& esac"),
+            "case $x in a | b) c ;; *.rs) d; e ;& *) f ;;& esac"
+        );
+        assert_eq!(
+            parse("case x\nin\n  a)\n    b\n    ;;\n  c) d\nesac"),
+            "case x in a) b ;; c) d ;; esac"
+        );
+        assert_eq!(parse("case x in a) ;; esac"), "case x in a) ;; esac");
+        assert_eq!(parse("case x in esac"), "case x in esac");
+    }
+
+    #[test]
+    fn invalid_case_statements() {
+        assert_eq!(
+            parse_error("case in a) b;; esac"),
+            ParseError::MissingEnd("in".to_string(), 2)
+        );
+        assert_eq!(parse_error("case; esac"), ParseError::WordIsEmpty(1));
+        assert_eq!(
+            parse_error("case x a) b;; esac"),
+            ParseError::MissingEnd("in".to_string(), 2)
+        );
+        assert_eq!(
+            parse_error("case x in a) b;;"),
+            ParseError::MissingEnd("esac".to_string(), 7)
+        );
+        assert_eq!(
+            parse_error("case x in ) b;; esac"),
+            ParseError::PatternIsEmpty(3)
+        );
+        assert_eq!(
+            parse_error("case x in a b) c;; esac"),
+            ParseError::MissingEnd(")".to_string(), 4)
+        );
+    }
+
+    #[test]
+    fn function_definitions() {
+        assert_eq!(parse("f() { a; }"), "f() { a; }");
+        assert_eq!(parse("function f { a; }"), "f() { a; }");
+        assert_eq!(parse("function f() (a)"), "f() (a)");
+        assert_eq!(
+            parse("f ()\n{\n  local x=1\n  return 2\n}"),
+            "f() { local x=1; return 2; }"
+        );
+        assert_eq!(parse("f() { a; } >log; f"), "f() { a; } 1>log; f");
+        assert_eq!(parse("f() if a; then b; fi"), "f() if a; then b; fi");
+    }
+
+    #[test]
+    fn invalid_function_definitions() {
+        assert_eq!(parse_error("f() a"), ParseError::InvalidFunctionBody(3));
+        assert_eq!(
+            parse_error("f(a) { b; }"),
+            ParseError::MissingEnd(")".to_string(), 2)
+        );
+        assert_eq!(parse_error("1f() { a; }"), ParseError::InvalidName(0));
+        assert_eq!(parse_error("function { a; }"), ParseError::InvalidName(1));
+        assert_eq!(parse_error("f()"), ParseError::CommandIsEmpty(3));
+    }
+
+    #[test]
+    fn negation_and_time() {
+        assert_eq!(parse("! a | b && c"), "! a | b && c");
+        assert_eq!(parse("time a | b"), "time a | b");
+        assert_eq!(parse("time -p ! a"), "time -p ! a");
+        assert_eq!(parse("! { a; } >f"), "! { a; } 1>f");
+        assert_eq!(parse("a || ! b"), "a || ! b");
+        // only at the head of a pipeline
+        assert_eq!(parse("echo ! time"), "echo ! time");
+    }
+
+    #[test]
+    fn invalid_negation_and_time() {
+        assert_eq!(parse_error("! && a"), ParseError::CommandIsEmpty(1));
+        assert_eq!(parse_error("time -p | a"), ParseError::CommandIsEmpty(2));
+    }
+
+    #[test]
+    fn assignments_before_commands() {
+        assert_eq!(parse("A=1 B=$A cmd x=y"), "A=1 B=$A cmd x=y");
+        assert_eq!(parse("A=1 >out B=2"), "A=1 B=2 1>out");
+        assert_eq!(parse("A='a b' B= cmd"), "A='a b' B= cmd");
+        // words which are not "name=value" start the command
+        assert_eq!(parse("1A=1 cmd"), "1A=1 cmd");
+        assert_eq!(parse("=1 cmd"), "=1 cmd");
+        assert_eq!(parse("'A'=1 cmd"), "'A'=1 cmd");
+    }
+}
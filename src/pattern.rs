@@ -0,0 +1,197 @@
+use crate::expand::ExpChar;
+
+// named classes like "[:alpha:]" in brackets
+fn class_matches(name: &str, c: char) -> bool {
+    match name {
+        "alpha" => c.is_alphabetic(),
+        "digit" => c.is_ascii_digit(),
+        "alnum" => c.is_alphanumeric(),
+        "upper" => c.is_uppercase(),
+        "lower" => c.is_lowercase(),
+        "space" => c.is_whitespace(),
+        "blank" => c == ' ' || c == '\t',
+        "punct" => c.is_ascii_punctuation(),
+        "xdigit" => c.is_ascii_hexdigit(),
+        "cntrl" => c.is_control(),
+        _ => false,
+    }
+}
+
+// matches `c` against the bracket expression at the head of `p`, and returns the result and
+// the length of the expression. returns None if the bracket is not closed
+fn match_bracket(p: &[ExpChar], c: char) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negate = matches!(p.get(1), Some(('!' | '^', false)));
+    if negate {
+        i += 1;
+    }
+    let st = i;
+    let mut matched = false;
+    while i < p.len() {
+        let (ch, quoted) = p[i];
+        // ']' just after '[' is a normal char
+        if ch == ']' && !quoted && i != st {
+            return Some((matched != negate, i + 1));
+        }
+        if ch == '[' && !quoted && p.get(i + 1) == Some(&(':', false)) {
+            let end = (i + 2..p.len().saturating_sub(1))
+                .find(|&j| p[j] == (':', false) && p[j + 1] == (']', false));
+            if let Some(end) = end {
+                let name = p[i + 2..end].iter().map(|&(c, _)| c).collect::<String>();
+                matched |= class_matches(&name, c);
+                i = end + 2;
+                continue;
+            }
+        }
+        if i + 2 < p.len() && p[i + 1] == ('-', false) && p[i + 2] != (']', false) {
+            matched |= ch <= c && c <= p[i + 2].0;
+            i += 3;
+            continue;
+        }
+        matched |= ch == c;
+        i += 1;
+    }
+    None
+}
+
+// returns true if the pattern has unquoted '*', '?' or "[...]"
+pub fn has_glob(p: &[ExpChar]) -> bool {
+    (0..p.len()).any(|i| match p[i] {
+        ('*' | '?', false) => true,
+        ('[', false) => match_bracket(&p[i..], '\0').is_some(),
+        _ => false,
+    })
+}
+
+// the length of the pattern matched by `c` at the head of `p`
+fn match_one(p: &[ExpChar], c: char) -> Option<usize> {
+    match p.first()? {
+        ('?', false) => Some(1),
+        ('[', false) => match match_bracket(p, c) {
+            Some((true, len)) => Some(len),
+            Some((false, _)) => None,
+            // an unclosed bracket is a normal char
+            None => (c == '[').then_some(1),
+        },
+        &(ch, _) => (ch == c).then_some(1),
+    }
+}
+
+// matches the whole of `s` against a pattern of '*', '?' and "[...]".
+// quoted chars of the pattern are matched literally
+pub fn matches(p: &[ExpChar], s: &[char]) -> bool {
+    let mut pi = 0;
+    let mut si = 0;
+    // the position of the last '*' and the position of `s` where it starts to match
+    let mut star: Option<(usize, usize)> = None;
+    while si < s.len() {
+        if p.get(pi) == Some(&('*', false)) {
+            star = Some((pi, si));
+            pi += 1;
+            continue;
+        }
+        if let Some(len) = match_one(&p[pi..], s[si]) {
+            pi += len;
+            si += 1;
+            continue;
+        }
+        // let the last '*' match one more char
+        match star {
+            Some((sp, ss)) => {
+                pi = sp + 1;
+                si = ss + 1;
+                star = Some((sp, ss + 1));
+            }
+            None => return false,
+        }
+    }
+    p[pi..].iter().all(|&x| x == ('*', false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unquoted(s: &str) -> Vec<ExpChar> {
+        s.chars().map(|c| (c, false)).collect()
+    }
+
+    fn is_match(p: &str, s: &str) -> bool {
+        matches(&unquoted(p), &s.chars().collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(is_match("a?c", "abc"));
+        assert!(!is_match("a?c", "ac"));
+        assert!(is_match("*", ""));
+        assert!(is_match("a*", "a"));
+        assert!(is_match("*.rs", "main.rs"));
+        assert!(!is_match("*.rs", "main.rsx"));
+        // the last '*' is retried at later positions
+        assert!(is_match("*a*b", "aXaYb"));
+        assert!(is_match("*ab*ab", "abXabab"));
+        assert!(!is_match("*a*b", "aXaYbc"));
+        assert!(is_match("**?", "x"));
+    }
+
+    #[test]
+    fn brackets() {
+        assert!(is_match("[abc]", "b"));
+        assert!(!is_match("[abc]", "d"));
+        assert!(is_match("[a-c]x", "bx"));
+        assert!(!is_match("[a-c]", "d"));
+        assert!(is_match("[!a-c]", "d"));
+        assert!(!is_match("[!a-c]", "a"));
+        assert!(is_match("[^a-c]", "d"));
+        assert!(!is_match("[^a-c]", "b"));
+        // ']' just after '[' or "[!" is a normal char, and so is '-' at the end
+        assert!(is_match("[]a]", "]"));
+        assert!(is_match("[]a]", "a"));
+        assert!(!is_match("[]a]", "b"));
+        assert!(is_match("[!]]", "a"));
+        assert!(!is_match("[!]]", "]"));
+        assert!(is_match("[a-]", "-"));
+        // an unclosed bracket is a normal char
+        assert!(is_match("[ab", "[ab"));
+        assert!(!is_match("[ab", "a"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(is_match("[[:alpha:]]", "x"));
+        assert!(!is_match("[[:alpha:]]", "1"));
+        assert!(is_match("[[:digit:]_]*", "_a"));
+        assert!(is_match("[![:space:]]", "a"));
+        assert!(!is_match("[![:space:]]", " "));
+        assert!(is_match("[[:upper:][:digit:]]", "Q"));
+        assert!(!is_match("[[:unknown:]]", "a"));
+    }
+
+    #[test]
+    fn quoted_chars_are_literal() {
+        let chars = |s: &str| s.chars().collect::<Vec<_>>();
+        let p = [unquoted("a"), vec![('*', true), ('?', true)]].concat();
+        assert!(matches(&p, &chars("a*?")));
+        assert!(!matches(&p, &chars("abc")));
+        let p = [vec![('[', true)], unquoted("ab]")].concat();
+        assert!(matches(&p, &chars("[ab]")));
+        assert!(!matches(&p, &chars("a")));
+        // quoted chars in brackets are members
+        let p = [unquoted("["), vec![('!', true)], unquoted("a]")].concat();
+        assert!(matches(&p, &chars("!")));
+        assert!(!matches(&p, &chars("b")));
+        let p = [unquoted("[a"), vec![('-', true)], unquoted("c]")].concat();
+        assert!(matches(&p, &chars("-")));
+        assert!(!matches(&p, &chars("b")));
+    }
+
+    #[test]
+    fn globs_need_unquoted_metacharacters() {
+        assert!(has_glob(&unquoted("a*")));
+        assert!(has_glob(&unquoted("a[b]")));
+        assert!(!has_glob(&unquoted("a[b")));
+        assert!(!has_glob(&unquoted("abc")));
+        assert!(!has_glob(&[('*', true), ('?', true)]));
+    }
+}
@@ -16,7 +16,7 @@ pub struct Trie {
 
 impl Trie {
     pub fn new(texts: Vec<String>, hist: &Vec<(i32, String)>) -> Trie {
-        let mut byte_texts = texts
+        let byte_texts = texts
             .iter()
             .map(|x| x.as_bytes())
             .collect::<BTreeSet<_>>()
@@ -25,7 +25,7 @@ impl Trie {
             .collect::<Vec<_>>();
         let texts = byte_texts
             .iter()
-            .map(|x| String::from_utf8(x.iter().cloned().collect::<Vec<_>>()).unwrap())
+            .map(|x| String::from_utf8(x.to_vec()).unwrap())
             .collect::<Vec<_>>();
         let n = texts.len();
         let mut nodes = Vec::new();
@@ -36,7 +36,7 @@ impl Trie {
             counts: vec![0; n],
             idx: Some(0),
         };
-        trie.read_history(&hist);
+        trie.read_history(hist);
         trie
     }
     fn read_history(&mut self, hist: &Vec<(i32, String)>) {
@@ -54,7 +54,7 @@ impl Trie {
             }
         }
     }
-    pub fn add_cnt(&mut self, cmd: &String) {
+    pub fn add_cnt(&mut self, cmd: &str) {
         for c in cmd.chars() {
             self.search(c);
         }
@@ -0,0 +1,178 @@
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::wait::{WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use std::mem::MaybeUninit;
+use std::time::Duration;
+
+// the default of TIMEFORMAT, and the format of "time -p"
+pub const DEFAULT_FORMAT: &str = "\nreal\t%3lR\nuser\t%3lU\nsys\t%3lS";
+pub const POSIX_FORMAT: &str = "real %2R\nuser %2U\nsys %2S";
+
+// user and system time
+#[derive(Clone, Copy, Default)]
+pub struct Usage {
+    pub user: Duration,
+    pub sys: Duration,
+}
+
+impl Usage {
+    fn from_rusage(usage: &libc::rusage) -> Usage {
+        let duration = |x: libc::timeval| {
+            Duration::from_secs(x.tv_sec as u64) + Duration::from_micros(x.tv_usec as u64)
+        };
+        Usage {
+            user: duration(usage.ru_utime),
+            sys: duration(usage.ru_stime),
+        }
+    }
+    pub fn add(&mut self, other: Usage) {
+        self.user += other.user;
+        self.sys += other.sys;
+    }
+    pub fn since(&self, start: Usage) -> Usage {
+        Usage {
+            user: self.user.saturating_sub(start.user),
+            sys: self.sys.saturating_sub(start.sys),
+        }
+    }
+}
+
+// usage of the shell process itself
+pub fn self_usage() -> Usage {
+    let mut usage = MaybeUninit::<libc::rusage>::zeroed();
+    unsafe {
+        libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr());
+        Usage::from_rusage(&usage.assume_init())
+    }
+}
+
+// waitpid which also returns the usage of the child, including its children waited by it
+pub fn wait4(pid: Pid, options: Option<WaitPidFlag>) -> nix::Result<(WaitStatus, Usage)> {
+    let mut status = 0;
+    let mut usage = MaybeUninit::<libc::rusage>::zeroed();
+    let options = options.map_or(0, |x| x.bits());
+    let res = unsafe { libc::wait4(pid.as_raw(), &mut status, options, usage.as_mut_ptr()) };
+    let pid = Errno::result(res)?;
+    let usage = unsafe { usage.assume_init() };
+    Ok((
+        WaitStatus::from_raw(Pid::from_raw(pid), status)?,
+        Usage::from_rusage(&usage),
+    ))
+}
+
+fn format_duration(duration: Duration, precision: usize, long: bool) -> String {
+    let secs = duration.as_secs_f64();
+    if long {
+        let mins = (secs / 60.0).floor();
+        format!("{}m{:.*}s", mins, precision, secs - mins * 60.0)
+    } else {
+        format!("{:.*}", precision, secs)
+    }
+}
+
+// formats times like TIMEFORMAT of bash. "%[p][l]R", "%[p][l]U" and "%[p][l]S" are the real,
+// user and system time with `p` digits after the point (3 at most), in "MmS.FFs" with 'l'.
+// "%P" is the CPU percentage
+pub fn format_times(format: &str, real: Duration, usage: Usage) -> String {
+    let s = format.chars().collect::<Vec<_>>();
+    let mut res = String::new();
+    let mut i = 0;
+    while i < s.len() {
+        if s[i] != '%' {
+            res.push(s[i]);
+            i += 1;
+            continue;
+        }
+        let mut j = i + 1;
+        let mut precision = 3;
+        if let Some(d) = s.get(j).and_then(|c| c.to_digit(10)) {
+            precision = (d as usize).min(3);
+            j += 1;
+        }
+        let long = s.get(j) == Some(&'l');
+        if long {
+            j += 1;
+        }
+        let duration = match s.get(j) {
+            Some('R') => Some(real),
+            Some('U') => Some(usage.user),
+            Some('S') => Some(usage.sys),
+            _ => None,
+        };
+        match (duration, s.get(i + 1)) {
+            (Some(duration), _) => {
+                res.push_str(&format_duration(duration, precision, long));
+                i = j + 1;
+            }
+            (None, Some('%')) => {
+                res.push('%');
+                i += 2;
+            }
+            (None, Some('P')) => {
+                let cpu = (usage.user + usage.sys).as_secs_f64();
+                let real = real.as_secs_f64();
+                let percent = if real > 0.0 { cpu / real * 100.0 } else { 0.0 };
+                res.push_str(&format!("{:.2}", percent));
+                i += 2;
+            }
+            // not a format
+            (None, _) => {
+                res.push('%');
+                i += 1;
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(user: u64, sys: u64) -> Usage {
+        Usage {
+            user: Duration::from_millis(user),
+            sys: Duration::from_millis(sys),
+        }
+    }
+
+    #[test]
+    fn formats_like_bash() {
+        let real = Duration::from_millis(61_500);
+        let usage = usage(1_250, 250);
+        assert_eq!(
+            format_times(DEFAULT_FORMAT, real, usage),
+            "\nreal\t1m1.500s\nuser\t0m1.250s\nsys\t0m0.250s"
+        );
+        assert_eq!(
+            format_times(POSIX_FORMAT, real, usage),
+            "real 61.50\nuser 1.25\nsys 0.25"
+        );
+        assert_eq!(format_times("%R %0U %1lS", real, usage), "61.500 1 0m0.2s");
+        assert_eq!(format_times("%9R", real, usage), "61.500");
+        assert_eq!(format_times("%P%%", real, usage), "2.44%");
+    }
+
+    #[test]
+    fn unknown_formats_are_kept() {
+        let real = Duration::from_secs(1);
+        assert_eq!(format_times("%x %3 %", real, usage(0, 0)), "%x %3 %");
+        assert_eq!(format_times("%P", Duration::ZERO, usage(0, 0)), "0.00");
+    }
+
+    #[test]
+    fn usage_differences() {
+        let mut total = usage(100, 200);
+        total.add(usage(10, 20));
+        assert_eq!(
+            (total.user, total.sys),
+            (usage(110, 220).user, usage(110, 220).sys)
+        );
+        let diff = usage(100, 200).since(usage(150, 50));
+        assert_eq!(
+            (diff.user, diff.sys),
+            (Duration::ZERO, Duration::from_millis(150))
+        );
+    }
+}
@@ -1,14 +1,19 @@
-use crate::execute::{CdError, ExecutionError};
+use crate::execute::ExecutionError;
+use crate::expand::{expand_braces, expand_word, to_string, ExpansionError};
+use crate::glob::glob;
 use crate::lexer::LexError;
-use crate::lexer::Token;
-use crate::parser::ParseError;
+use crate::lexer::Span;
+use crate::parser::{ParseError, Stage};
+use crate::pattern::has_glob;
 use crate::search::Trie;
+use crate::time::Usage;
+use colored::Colorize;
+use std::collections::HashMap;
 use std::fmt::{Debug, Display, Formatter};
 use std::fs::{create_dir, File};
 use std::io::{BufRead, BufReader};
 use std::io::{BufWriter, Write};
 use std::path::PathBuf;
-use thiserror::Error;
 
 #[macro_export]
 macro_rules! println2 {
@@ -18,15 +23,74 @@ macro_rules! println2 {
         print!("\n\r");
     })
 }
-pub(crate) use println2;
-use std::collections::BTreeSet;
-use std::env::VarError;
+
+// for errors in child processes, whose stdout may be captured
+#[macro_export]
+macro_rules! eprintln2 {
+    ($($arg:tt)*) => ({
+        eprint!($($arg)*);
+        eprint!("\n\r");
+    })
+}
 
 #[derive(Clone, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum ErrorEnum {
     ParseError(ParseError),
     LexError(LexError),
     ExecutionError(ExecutionError),
+    Located(Box<ErrorEnum>, Location),
+}
+
+// the line of the input pointed by an error, shown under the error message
+#[derive(Clone, Debug)]
+pub struct Location {
+    pub line: String,
+    // shown only if the input has multiple lines
+    pub line_no: Option<usize>,
+    pub column: usize,
+    pub width: usize,
+    pub hint: String,
+}
+
+impl Location {
+    pub fn new(s: &str, span: Span, hint: String) -> Location {
+        let lines = s.split('\n').collect::<Vec<_>>();
+        let mut start = 0;
+        for (i, line) in lines.iter().enumerate() {
+            let len = line.chars().count();
+            if span.start <= start + len || i + 1 == lines.len() {
+                let column = span.start - start;
+                return Location {
+                    line: line.to_string(),
+                    line_no: if lines.len() == 1 { None } else { Some(i + 1) },
+                    column,
+                    width: span.end.min(start + len).saturating_sub(span.start).max(1),
+                    hint,
+                };
+            }
+            start += len + 1;
+        }
+        unreachable!()
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let prefix = match self.line_no {
+            Some(no) => format!("{:4} | ", no),
+            None => "     | ".to_string(),
+        };
+        writeln!(f, "{}{}", prefix, self.line)?;
+        write!(
+            f,
+            "{}{}{} {}",
+            " ".repeat(prefix.len()),
+            " ".repeat(self.column),
+            "^".repeat(self.width).red(),
+            self.hint
+        )
+    }
 }
 
 impl Display for ErrorEnum {
@@ -44,6 +108,21 @@ impl Display for ErrorEnum {
             ErrorEnum::ExecutionError(ExecutionError::HistoryError(err)) => {
                 write!(f, "history: {}", err.clone())
             }
+            ErrorEnum::ExecutionError(ExecutionError::SetError(err)) => {
+                write!(f, "set: {}", err.clone())
+            }
+            ErrorEnum::ExecutionError(ExecutionError::VarError(name, err)) => {
+                write!(f, "{}: {}", name, err.clone())
+            }
+            ErrorEnum::ExecutionError(ExecutionError::LoopError(name, err)) => {
+                write!(f, "{}: {}", name, err.clone())
+            }
+            ErrorEnum::ExecutionError(ExecutionError::FunctionError(name, err)) => {
+                write!(f, "{}: {}", name, err.clone())
+            }
+            ErrorEnum::ExecutionError(ExecutionError::ExpansionError(err)) => {
+                write!(f, "myshell: {}", err.clone())
+            }
             ErrorEnum::ExecutionError(ExecutionError::InterruptError) => {
                 write!(f, "interrupted")
             }
@@ -53,25 +132,33 @@ impl Display for ErrorEnum {
             ErrorEnum::ExecutionError(err) => {
                 write!(f, "ExecutionError: {}", err.clone())
             }
+            ErrorEnum::Located(err, location) => {
+                write!(f, "{}\n{}", err, location)
+            }
         }
     }
 }
 
+// each entry is stored as "<status> <command>", and the following lines of a multi-line command
+// are stored with a leading tab
 fn get_history(history_file: &Option<File>) -> Vec<(i32, String)> {
     match &history_file {
         Some(file) => {
             let reader = BufReader::new(file);
-            reader
-                .lines()
-                .filter_map(|x| x.ok())
-                .filter_map(|x| match x.find(' ') {
-                    Some(idx) => match &x[..idx].parse::<i32>() {
-                        Ok(status) => Some((status.clone(), x[idx + 1..].to_string())),
-                        Err(_) => None,
-                    },
-                    None => None,
-                })
-                .collect::<Vec<_>>()
+            let mut history: Vec<(i32, String)> = Vec::new();
+            for x in reader.lines().map_while(Result::ok) {
+                if let Some(line) = x.strip_prefix('\t') {
+                    if let Some((_, cmd)) = history.last_mut() {
+                        cmd.push('\n');
+                        cmd.push_str(line);
+                    }
+                } else if let Some(idx) = x.find(' ') {
+                    if let Ok(status) = x[..idx].parse::<i32>() {
+                        history.push((status, x[idx + 1..].to_string()));
+                    }
+                }
+            }
+            history
         }
         None => Vec::new(),
     }
@@ -81,7 +168,7 @@ fn get_path() -> Vec<String> {
     let paths = match std::env::var("PATH") {
         Ok(path) => path.split(":").map(|x| x.to_string()).collect(),
         Err(err) => {
-            println!("myshell: failed to load PATH ({})", err.to_string());
+            println!("myshell: failed to load PATH ({})", err);
             Vec::new()
         }
     };
@@ -90,7 +177,7 @@ fn get_path() -> Vec<String> {
         if let Ok(bins) = std::fs::read_dir(path) {
             res.extend(bins.filter_map(|x| {
                 if let Ok(bin) = x {
-                    if bin.file_type().map_or(false, |x| !x.is_dir()) {
+                    if bin.file_type().is_ok_and(|x| !x.is_dir()) {
                         if let Some(file_name) = bin.path().file_name() {
                             return Some(file_name.to_str().unwrap().to_string());
                         }
@@ -103,6 +190,14 @@ fn get_path() -> Vec<String> {
     res.push("cd".to_string());
     res.push("exit".to_string());
     res.push("history".to_string());
+    res.push("set".to_string());
+    res.push("export".to_string());
+    res.push("unset".to_string());
+    res.push("read".to_string());
+    res.push("break".to_string());
+    res.push("continue".to_string());
+    res.push("local".to_string());
+    res.push("return".to_string());
     res
 }
 
@@ -112,9 +207,40 @@ pub struct Env {
     pub home_dir: PathBuf,
     pub path_set: Trie,
     pub history: Vec<(i32, String)>,
+    #[allow(dead_code)]
     pub config_dir: PathBuf,
     pub history_file: Option<File>,
     pub auto_exec_path: PathBuf,
+    // set -C
+    pub noclobber: bool,
+    // set -o nullglob and set -o failglob, for patterns which match nothing
+    pub nullglob: bool,
+    pub failglob: bool,
+    // shell variables. exported ones are also set in the environment of the process
+    pub vars: HashMap<String, String>,
+    // false in tests, which must not change the environment of the process
+    pub sync_env: bool,
+    // $0, $1... and $$
+    pub shell_name: String,
+    pub params: Vec<String>,
+    pub shell_pid: i32,
+    // $? and $!
+    pub last_status: i32,
+    pub last_bg_pid: Option<i32>,
+    // whether a command substitution has run while the current command is expanded, for the
+    // status of a command without words
+    pub substituted: bool,
+    // fds and children of process substitutions for the command being started
+    pub aux_fds: Vec<i32>,
+    pub aux_children: Vec<i32>,
+    // the number of loops being executed, for "break" and "continue"
+    pub loop_depth: usize,
+    pub functions: HashMap<String, Stage>,
+    // for each function being executed, the variables made local and their old values, with
+    // whether they were exported
+    pub local_scopes: Vec<Vec<(String, Option<String>, bool)>>,
+    // resource usage of the children waited by the shell, for "time"
+    pub child_usage: Usage,
 }
 
 impl Env {
@@ -127,25 +253,17 @@ impl Env {
         let history_path = config_dir.join(HISTORY_PATH);
         let auto_exec_path = config_dir.join(AUTO_EXEC_PATH);
 
-        create_dir(&config_dir);
+        let _ = create_dir(&config_dir);
         if !history_path.exists() {
-            File::create(&history_path);
+            let _ = File::create(&history_path);
         }
         if !auto_exec_path.exists() {
-            File::create(&auto_exec_path);
+            let _ = File::create(&auto_exec_path);
         }
-        let history_file = match File::options()
-            .read(true)
-            .write(true)
-            .append(true)
-            .open(&history_path)
-        {
+        let history_file = match File::options().read(true).append(true).open(&history_path) {
             Ok(file) => Some(file),
             Err(err) => {
-                println!(
-                    "myshell: failed to load the history file ({})",
-                    err.to_string()
-                );
+                println!("myshell: failed to load the history file ({})", err);
                 None
             }
         };
@@ -153,40 +271,223 @@ impl Env {
 
         let paths = get_path();
         let path_set = Trie::new(paths, &history);
+        let mut args = std::env::args();
 
         Env {
             user_name: whoami::username(),
-            host_name: whoami::hostname(),
+            host_name: whoami::fallible::hostname().unwrap_or_else(|_| "???".to_string()),
             path_set,
             history,
             home_dir,
             config_dir,
             history_file,
             auto_exec_path,
+            noclobber: false,
+            nullglob: false,
+            failglob: false,
+            vars: std::env::vars().collect(),
+            sync_env: true,
+            shell_name: args.next().unwrap_or_else(|| "myshell".to_string()),
+            params: args.collect(),
+            shell_pid: std::process::id() as i32,
+            last_status: 0,
+            last_bg_pid: None,
+            substituted: false,
+            aux_fds: Vec::new(),
+            aux_children: Vec::new(),
+            loop_depth: 0,
+            functions: HashMap::new(),
+            local_scopes: Vec::new(),
+            child_usage: Usage::default(),
+        }
+    }
+
+    // an environment which uses neither the config files nor the environment of the process
+    #[cfg(test)]
+    pub fn for_test() -> Env {
+        Env {
+            user_name: "user".to_string(),
+            host_name: "host".to_string(),
+            path_set: Trie::new(Vec::new(), &Vec::new()),
+            history: Vec::new(),
+            home_dir: PathBuf::new(),
+            config_dir: PathBuf::new(),
+            history_file: None,
+            auto_exec_path: PathBuf::new(),
+            noclobber: false,
+            nullglob: false,
+            failglob: false,
+            vars: HashMap::new(),
+            sync_env: false,
+            shell_name: "myshell".to_string(),
+            params: Vec::new(),
+            shell_pid: std::process::id() as i32,
+            last_status: 0,
+            last_bg_pid: None,
+            substituted: false,
+            aux_fds: Vec::new(),
+            aux_children: Vec::new(),
+            loop_depth: 0,
+            functions: HashMap::new(),
+            local_scopes: Vec::new(),
+            child_usage: Usage::default(),
+        }
+    }
+
+    pub fn get_var(&self, name: &str) -> Option<String> {
+        self.vars.get(name).cloned()
+    }
+
+    pub fn set_var(&mut self, name: &str, value: &str) {
+        self.vars.insert(name.to_string(), value.to_string());
+        if self.sync_env && std::env::var_os(name).is_some() {
+            std::env::set_var(name, value);
         }
     }
 
+    pub fn unset_var(&mut self, name: &str) {
+        self.vars.remove(name);
+        std::env::remove_var(name);
+    }
+
+    pub fn export_var(&mut self, name: &str) {
+        std::env::set_var(name, self.vars.get(name).cloned().unwrap_or_default());
+    }
+
     pub fn push_history(&mut self, cmd: String, status: i32) {
+        self.last_status = status;
         self.history.push((status, cmd.clone()));
         if let Some(file) = &self.history_file {
             let mut writer = BufWriter::new(file);
-            writeln!(writer, "{} {}", status, cmd.clone());
+            let _ = writeln!(writer, "{} {}", status, cmd.replace('\n', "\n\t"));
         }
         if let Some(head) = cmd.split_ascii_whitespace().next() {
-            self.path_set.add_cnt(&head.to_string());
+            self.path_set.add_cnt(head);
         }
     }
 }
 
-pub fn replace_tokens(tokens: Vec<Token>, env: &Env) -> Vec<Token> {
-    tokens
-        .iter()
-        .map(|t| match t {
-            Token::Operator(_) => t.clone(),
-            Token::String(s) => Token::String(match s.strip_prefix("~") {
-                None => s.clone(),
-                Some(suff) => env.home_dir.display().to_string() + suff,
-            }),
-        })
-        .collect()
+// removes quotes and backslashes without expansions (e.g. the delimiter of a here-document)
+pub fn remove_quotes(s: &str) -> String {
+    let s = s.chars().collect::<Vec<_>>();
+    let n = s.len();
+    let mut res = String::new();
+    let mut i = 0;
+    while i < n {
+        match s[i] {
+            '\\' if i + 1 < n => {
+                if s[i + 1] != '\n' {
+                    res.push(s[i + 1]);
+                }
+                i += 2;
+            }
+            '\'' => {
+                i += 1;
+                while i < n && s[i] != '\'' {
+                    res.push(s[i]);
+                    i += 1;
+                }
+                i += 1;
+            }
+            '"' => {
+                i += 1;
+                while i < n && s[i] != '"' {
+                    if s[i] == '\\' && i + 1 < n && s[i + 1] == '\n' {
+                        i += 2;
+                        continue;
+                    }
+                    if s[i] == '\\' && i + 1 < n && ['$', '`', '"', '\\'].contains(&s[i + 1]) {
+                        i += 1;
+                    }
+                    res.push(s[i]);
+                    i += 1;
+                }
+                i += 1;
+            }
+            ch => {
+                res.push(ch);
+                i += 1;
+            }
+        }
+    }
+    res
+}
+
+pub fn is_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// expands words of a command just before it is executed
+pub fn replace_tokens(words: &[String], env: &mut Env) -> Result<Vec<String>, ExpansionError> {
+    let mut res = Vec::new();
+    for word in words.iter().flat_map(|x| expand_braces(x)) {
+        for field in expand_word(&word, env)? {
+            if !has_glob(&field) {
+                res.push(to_string(&field));
+                continue;
+            }
+            let paths = glob(&field);
+            if !paths.is_empty() {
+                res.extend(paths);
+            } else if env.failglob {
+                return Err(ExpansionError::NoMatch(to_string(&field)));
+            } else if !env.nullglob {
+                res.push(to_string(&field));
+            }
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remove_quotes_keeps_escaped_chars() {
+        assert_eq!(remove_quotes(r#"'a b'"c d"e\ f"#), "a bc de f");
+        assert_eq!(remove_quotes(r#"'\"$x'"#), r#"\"$x"#);
+        // backslashes in double quotes escape only some chars
+        assert_eq!(remove_quotes(r#""\$\a\"\\""#), r#"$\a"\"#);
+    }
+
+    #[test]
+    fn location_points_the_line_of_the_span() {
+        let location = Location::new("echo a | | b", Span::new(9, 10), String::new());
+        assert_eq!(location.line, "echo a | | b");
+        assert_eq!(
+            (location.line_no, location.column, location.width),
+            (None, 9, 1)
+        );
+        let location = Location::new("if a\nthen b c\nfi", Span::new(12, 13), String::new());
+        assert_eq!(location.line, "then b c");
+        assert_eq!(
+            (location.line_no, location.column, location.width),
+            (Some(2), 7, 1)
+        );
+        // a span at the end of input points just after the last char
+        let location = Location::new("a &&", Span::new(4, 5), String::new());
+        assert_eq!((location.column, location.width), (4, 1));
+    }
+
+    #[test]
+    fn patterns_matching_nothing() {
+        let mut env = Env::for_test();
+        let words = ["/nonexistent/*.x".to_string(), "'/*'".to_string()];
+        assert_eq!(
+            replace_tokens(&words, &mut env).unwrap(),
+            ["/nonexistent/*.x", "/*"]
+        );
+        env.nullglob = true;
+        assert_eq!(replace_tokens(&words, &mut env).unwrap(), ["/*"]);
+        env.failglob = true;
+        assert!(matches!(
+            replace_tokens(&words, &mut env),
+            Err(ExpansionError::NoMatch(x)) if x == "/nonexistent/*.x"
+        ));
+    }
 }